@@ -0,0 +1,203 @@
+use clap::ValueEnum;
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+/// A command along with the subcommands and flags that can follow it
+struct Node {
+    /// Subcommand names leading to this command, empty for the root
+    path: Vec<String>,
+    subcommands: Vec<(String, String)>,
+    flags: Vec<Flag>,
+}
+
+struct Flag {
+    long: Option<String>,
+    short: Option<char>,
+    about: String,
+}
+
+impl Node {
+    fn key(&self) -> String {
+        self.path.join(" ")
+    }
+
+    fn words(&self) -> Vec<String> {
+        let subcommands = self.subcommands.iter().map(|(name, _)| name.clone());
+        let flags = self.flags.iter().flat_map(|flag| {
+            let long = flag.long.iter().map(|long| format!("--{long}"));
+            let short = flag.short.iter().map(|short| format!("-{short}"));
+            long.chain(short).collect::<Vec<_>>()
+        });
+        subcommands.chain(flags).collect()
+    }
+}
+
+/// Builds a completion script for `shell` covering the subcommands and flags of `command`
+pub fn generate(shell: Shell, mut command: clap::Command) -> String {
+    // Building propagates the global flags down to the subcommands
+    command.build();
+    let name = command.get_name().to_owned();
+    let mut nodes = Vec::new();
+    collect(&command, Vec::new(), &mut nodes);
+
+    match shell {
+        Shell::Bash => bash(&name, &nodes),
+        Shell::Zsh => zsh(&name, &nodes),
+        Shell::Fish => fish(&name, &nodes),
+        Shell::Powershell => powershell(&name, &nodes),
+    }
+}
+
+fn collect(command: &clap::Command, path: Vec<String>, nodes: &mut Vec<Node>) {
+    let about = |about: Option<&clap::builder::StyledStr>| {
+        about.map(|about| about.to_string()).unwrap_or_default()
+    };
+    let flags = command
+        .get_arguments()
+        .filter(|arg| !arg.is_positional())
+        .map(|arg| Flag {
+            long: arg.get_long().map(str::to_owned),
+            short: arg.get_short(),
+            about: about(arg.get_help()),
+        })
+        .collect();
+    let subcommands = command
+        .get_subcommands()
+        .map(|sub| (sub.get_name().to_owned(), about(sub.get_about())))
+        .collect();
+    nodes.push(Node {
+        path: path.clone(),
+        subcommands,
+        flags,
+    });
+
+    for sub in command.get_subcommands() {
+        let mut path = path.clone();
+        path.push(sub.get_name().to_owned());
+        collect(sub, path, nodes);
+    }
+}
+
+/// Shell case arms moving from one command to its subcommand, as `(from, word, to)`
+fn transitions(nodes: &[Node]) -> Vec<(String, String, String)> {
+    nodes
+        .iter()
+        .flat_map(|node| {
+            node.subcommands.iter().map(|(name, _)| {
+                let mut path = node.path.clone();
+                path.push(name.clone());
+                (node.key(), name.clone(), path.join(" "))
+            })
+        })
+        .collect()
+}
+
+fn bash(name: &str, nodes: &[Node]) -> String {
+    let function = format!("_{}", name.replace('-', "_"));
+    let mut script = format!(
+        "{function}() {{\n    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"\n    local cmd=\"\"\n    local word\n    for word in \"${{COMP_WORDS[@]:1:COMP_CWORD-1}}\"; do\n        case \"$cmd/$word\" in\n"
+    );
+    for (from, word, to) in transitions(nodes) {
+        script.push_str(&format!("            \"{from}/{word}\") cmd=\"{to}\" ;;\n"));
+    }
+    script.push_str("        esac\n    done\n\n    local opts=\"\"\n    case \"$cmd\" in\n");
+    for node in nodes {
+        script.push_str(&format!(
+            "        \"{}\") opts=\"{}\" ;;\n",
+            node.key(),
+            node.words().join(" ")
+        ));
+    }
+    script.push_str(&format!(
+        "    esac\n    COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))\n}}\n\ncomplete -F {function} -o default {name}\n"
+    ));
+    script
+}
+
+fn zsh(name: &str, nodes: &[Node]) -> String {
+    let function = format!("_{}", name.replace('-', "_"));
+    let mut script = format!(
+        "#compdef {name}\n\n{function}() {{\n    local cmd=\"\"\n    local word\n    for word in \"${{(@)words[2,CURRENT-1]}}\"; do\n        case \"$cmd/$word\" in\n"
+    );
+    for (from, word, to) in transitions(nodes) {
+        script.push_str(&format!("            \"{from}/{word}\") cmd=\"{to}\" ;;\n"));
+    }
+    script.push_str("        esac\n    done\n\n    local -a opts\n    case \"$cmd\" in\n");
+    for node in nodes {
+        script.push_str(&format!(
+            "        \"{}\") opts=({}) ;;\n",
+            node.key(),
+            node.words().join(" ")
+        ));
+    }
+    script.push_str(&format!(
+        "    esac\n    compadd -- $opts\n    _files\n}}\n\ncompdef {function} {name}\n"
+    ));
+    script
+}
+
+fn fish(name: &str, nodes: &[Node]) -> String {
+    let mut script = String::new();
+    for node in nodes {
+        let condition = match node.path.last() {
+            None => "__fish_use_subcommand".to_owned(),
+            Some(last) => format!("__fish_seen_subcommand_from {last}"),
+        };
+        for (sub, about) in node.subcommands.iter() {
+            script.push_str(&format!(
+                "complete -c {name} -n '{condition}' -f -a {sub} -d '{}'\n",
+                fish_escape(about)
+            ));
+        }
+        for flag in node.flags.iter() {
+            let mut line = format!("complete -c {name}");
+            if !node.path.is_empty() {
+                line.push_str(&format!(" -n '{condition}'"));
+            }
+            if let Some(long) = &flag.long {
+                line.push_str(&format!(" -l {long}"));
+            }
+            if let Some(short) = flag.short {
+                line.push_str(&format!(" -s {short}"));
+            }
+            line.push_str(&format!(" -d '{}'\n", fish_escape(&flag.about)));
+            script.push_str(&line);
+        }
+    }
+    script
+}
+
+fn fish_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+fn powershell(name: &str, nodes: &[Node]) -> String {
+    let mut script = format!(
+        "Register-ArgumentCompleter -Native -CommandName '{name}' -ScriptBlock {{\n    param($wordToComplete, $commandAst, $cursorPosition)\n\n    $cmd = ''\n    foreach ($element in $commandAst.CommandElements | Select-Object -Skip 1) {{\n        if ($element.Extent.StartOffset -ge $cursorPosition) {{ break }}\n        switch (\"$cmd/$element\") {{\n"
+    );
+    for (from, word, to) in transitions(nodes) {
+        script.push_str(&format!(
+            "            '{from}/{word}' {{ $cmd = '{to}' }}\n"
+        ));
+    }
+    script.push_str("        }\n    }\n\n    $opts = switch ($cmd) {\n");
+    for node in nodes {
+        let words = node
+            .words()
+            .iter()
+            .map(|word| format!("'{word}'"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        script.push_str(&format!("        '{}' {{ @({words}) }}\n", node.key()));
+    }
+    script.push_str(
+        "    }\n    $opts | Where-Object { $_ -like \"$wordToComplete*\" } | ForEach-Object {\n        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)\n    }\n}\n",
+    );
+    script
+}
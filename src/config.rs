@@ -1,14 +1,23 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io;
 use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Context, Result};
 use colored::Colorize;
 use fs_extra::dir::CopyOptions;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::DottyError;
+use crate::shell::Shell;
+use crate::verbosity::Level;
+use crate::{history, host, layers};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(deny_unknown_fields)]
@@ -18,62 +27,251 @@ enum ShorthandOrTable<T> {
     Value(T),
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
-#[serde(from = "ShorthandOrTable<T>", into = "ShorthandOrTable<T>")]
-struct SupportsShorthand<T: From<Box<str>> + Clone>(T);
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(try_from = "ShorthandOrTable<T>", into = "ShorthandOrTable<T>")]
+#[serde(bound(deserialize = "T: Deserialize<'de>, T::Error: std::fmt::Display"))]
+struct SupportsShorthand<T: TryFrom<Box<str>> + Clone>(T);
 
-impl<T: From<Box<str>> + Clone> From<ShorthandOrTable<T>> for SupportsShorthand<T>
+impl<T: TryFrom<Box<str>> + Clone> TryFrom<ShorthandOrTable<T>> for SupportsShorthand<T>
 where
-    T: From<Box<str>>,
+    T::Error: std::fmt::Display,
 {
-    fn from(value: ShorthandOrTable<T>) -> Self {
+    type Error = T::Error;
+
+    fn try_from(value: ShorthandOrTable<T>) -> Result<Self, Self::Error> {
         match value {
-            ShorthandOrTable::String(x) => Self(x.into()),
-            ShorthandOrTable::Value(x) => Self(x),
+            ShorthandOrTable::String(x) => Ok(Self(x.try_into()?)),
+            ShorthandOrTable::Value(x) => Ok(Self(x)),
         }
     }
 }
 
-impl<T: From<Box<str>> + Clone> From<SupportsShorthand<T>> for ShorthandOrTable<T> {
+impl<T: TryFrom<Box<str>> + Clone> From<SupportsShorthand<T>> for ShorthandOrTable<T> {
     fn from(value: SupportsShorthand<T>) -> Self {
         ShorthandOrTable::Value(value.0)
     }
 }
 
-impl<T: From<Box<str>> + Clone> Deref for SupportsShorthand<T> {
+impl<T: TryFrom<Box<str>> + Clone> Deref for SupportsShorthand<T> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
         &self.0
     }
 }
-impl<T: From<Box<str>> + Clone> DerefMut for SupportsShorthand<T> {
+impl<T: TryFrom<Box<str>> + Clone> DerefMut for SupportsShorthand<T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.0
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+/// Priority of entries without one, when `[dotty]` does not set `default_priority`
+const DEFAULT_PRIORITY: i32 = 50;
+/// Separator of managers without one, when `[dotty]` does not set `default_separator`
+const DEFAULT_SEPARATOR: &str = " ";
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
 #[serde(deny_unknown_fields)]
 #[serde(default)]
 pub struct Config {
     managers: HashMap<Box<str>, Manager>,
-    packages: HashMap<Box<str>, HashSet<Box<str>>>,
+    packages: HashMap<Box<str>, HashSet<SupportsShorthand<Package>>>,
+    /// Logical package names and the name every manager knows them by
+    aliases: HashMap<Box<str>, HashMap<Box<str>, Box<str>>>,
+    /// Aliases installed with every manager that has a name for them
+    shared_packages: HashSet<SupportsShorthand<Package>>,
     module: Module,
     dotty: DottyConfig,
     hooks: Hooks,
     files: HashMap<Box<str>, SupportsShorthand<File>>,
     template: TemplateContext,
+    /// Template values read from the output of commands when rendering, only the commands are
+    /// ever stored
+    secrets: HashMap<Box<str>, Secret>,
+    remove: Removals,
+    profiles: HashMap<Box<str>, Profile>,
+    /// Only used in the state, hashes of the sources deployed for each file
+    hashes: HashMap<Box<str>, Box<str>>,
+    /// Only used in the state, `once` hooks whose last run failed
+    failed_hooks: HashSet<Box<str>>,
+    /// Only used in the state, unix time each update hook with a `min_interval` last succeeded
+    hook_runs: HashMap<Box<str>, u64>,
+    /// The config file each manager, hook and file was last defined in
+    #[serde(skip)]
+    origins: HashMap<Box<str>, PathBuf>,
+    /// Every config file read by `load_dependencies`, the root first
+    #[serde(skip)]
+    modules: Vec<PathBuf>,
+    /// Every `git+` import loaded, so `dotty update` can pull them
+    #[serde(skip)]
+    git_modules: Vec<Box<str>>,
+    #[serde(skip)]
+    templater: Templater,
+}
+
+/// The `Tera` instance with the `template_includes` registered, built on first use so it is
+/// shared by every template rendered during a run, and the output of every template rendered so
+/// far so a source deployed to several targets is only rendered once
+#[derive(Default)]
+struct Templater {
+    tera: OnceLock<tera::Tera>,
+    rendered: Mutex<HashMap<PathBuf, String>>,
+    /// Outputs of the `[secrets]` commands, resolved the first time a template is rendered
+    secrets: OnceLock<HashMap<Box<str>, String>>,
+}
+
+impl Clone for Templater {
+    fn clone(&self) -> Self {
+        Self {
+            tera: self.tera.clone(),
+            rendered: Mutex::new(self.rendered.lock().unwrap().clone()),
+            secrets: self.secrets.clone(),
+        }
+    }
+}
+
+/// A template value read from the trimmed output of `command`, so it never has to be written
+/// down in the config
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Secret {
+    command: Box<str>,
+}
+
+impl std::fmt::Debug for Templater {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Templater")
+    }
+}
+
+/// Only a cache of other fields, so it never makes configs differ
+impl PartialEq for Templater {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Templater {}
+
+/// Extra entries that are only used when the profile is selected with `--profile`
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    packages: HashMap<Box<str>, HashSet<SupportsShorthand<Package>>>,
+    hooks: Hooks,
+    files: HashMap<Box<str>, SupportsShorthand<File>>,
+}
+
+impl Profile {
+    /// Adds the entries of a profile with the same name from another module, like
+    /// `Config::combine`
+    fn combine(&mut self, other: Profile, policy: MergePolicy) {
+        for (manager, packages) in other.packages {
+            self.packages.entry(manager).or_default().extend(packages);
+        }
+        policy.merge(&mut self.hooks.once, other.hooks.once);
+        policy.merge(&mut self.hooks.update, other.hooks.update);
+        policy.merge(&mut self.files, other.files);
+    }
+}
+
+/// Entries inherited from imported modules to delete again
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct Removals {
+    managers: HashSet<Box<str>>,
+    packages: HashMap<Box<str>, HashSet<Box<str>>>,
+    hooks: HashSet<Box<str>>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
 #[serde(default)]
 #[serde(deny_unknown_fields)]
 pub struct Module {
-    import: HashSet<Box<str>>,
+    /// Loaded in order, followed by the matching `import_if` modules sorted by name
+    import: Vec<Import>,
+    import_if: HashMap<Box<str>, Condition>,
     disable: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+/// A module path or `git+` import, or a git import written as a table
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(untagged)]
+enum Import {
+    Path(Box<str>),
+    Git(GitImport),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+struct GitImport {
+    git: Box<str>,
+    #[serde(rename = "ref")]
+    reference: Option<Box<str>>,
+    path: Option<Box<str>>,
+    /// Public key the module's `.minisig` signature has to verify against before it is loaded
+    minisign_pubkey: Option<Box<str>>,
+}
+
+impl Import {
+    /// The import as written in `import`, tables become `git+<url>?ref=<ref>#<path>`
+    fn spec(&self) -> Box<str> {
+        match self {
+            Self::Path(path) => path.clone(),
+            Self::Git(git) => {
+                let mut spec = format!("{GIT_PREFIX}{}", git.git);
+                if let Some(reference) = &git.reference {
+                    spec.push_str(&format!("?ref={reference}"));
+                }
+                if let Some(path) = &git.path {
+                    spec.push_str(&format!("#{path}"));
+                }
+                spec.into()
+            }
+        }
+    }
+
+    fn minisign_pubkey(&self) -> Option<&str> {
+        match self {
+            Self::Path(_) => None,
+            Self::Git(git) => git.minisign_pubkey.as_deref(),
+        }
+    }
+}
+
+/// A condition on the current host, every key that is set has to match
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    hostname: Option<Box<str>>,
+    os: Option<Box<str>>,
+    distro: Option<Box<str>>,
+}
+
+impl Condition {
+    pub fn matches(&self) -> Result<bool> {
+        if let Some(hostname) = &self.hostname {
+            if *host::hostname()? != **hostname {
+                return Ok(false);
+            }
+        }
+        if let Some(os) = &self.os {
+            if std::env::consts::OS != &**os {
+                return Ok(false);
+            }
+        }
+        if let Some(distro) = &self.distro {
+            if host::distro().as_deref() != Some(distro) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
 #[serde(default)]
 #[serde(deny_unknown_fields)]
 pub struct Hooks {
@@ -86,22 +284,242 @@ pub struct Hooks {
 #[serde(deny_unknown_fields)]
 pub struct File {
     source: Box<str>,
-    priority: u8,
+    /// `default_priority` from `[dotty]` when unset
+    priority: Option<i32>,
     post_hook: Option<Box<str>>,
     sudo: bool,
+    /// Disabled files are ignored entirely, unlike deleting the entry the deployed target is
+    /// left in place untouched.
+    enabled: bool,
+    /// Structured files deep-merged in order and written to the target instead of `source`
+    layers: Vec<Box<str>>,
+    /// Symlink the target to the source instead of copying it
+    link: bool,
+    /// Force rendering the source as a template or not, by default only `.tera` files are
+    template: Option<bool>,
+    /// Permissions set on the target after writing it as an octal string like `"0600"`, by
+    /// default copies keep the permissions of the source
+    mode: Option<Box<str>>,
+    /// Globs of entries left out when copying a directory, matched against the path relative
+    /// to the source and against the entry's name
+    exclude: Vec<Box<str>>,
+    /// Delete entries of a copied directory's target that are not in the source
+    mirror: bool,
+    /// More targets deployed from the same source besides the entry's own, templates are
+    /// rendered once for all of them
+    targets: Vec<Box<str>>,
+    /// User owning the target after a `sudo` copy, root by default
+    owner: Option<Box<str>>,
+    /// Group owning the target after a `sudo` copy
+    group: Option<Box<str>>,
+    /// Expected sha256 of the source as a hex string, applying refuses to copy a source that
+    /// does not match it
+    sha256: Option<Box<str>>,
 }
 
 impl Default for File {
     fn default() -> Self {
         Self {
             source: "".into(),
-            priority: 50,
+            priority: None,
             post_hook: None,
             sudo: false,
+            enabled: true,
+            layers: Vec::new(),
+            link: false,
+            template: None,
+            mode: None,
+            exclude: Vec::new(),
+            mirror: false,
+            targets: Vec::new(),
+            owner: None,
+            group: None,
+            sha256: None,
+        }
+    }
+}
+
+impl File {
+    /// Makes relative `source` and `layers` paths relative to `directory` instead of the working
+    /// directory
+    fn anchor(&mut self, directory: &Path) {
+        anchor_path(&mut self.source, directory);
+        for layer in self.layers.iter_mut() {
+            anchor_path(layer, directory);
+        }
+    }
+
+    fn resolve_target(&self, name: &str) -> PathBuf {
+        // Resolving a link target fully would follow the link to the source
+        if self.link {
+            resolve_link_path(name)
+        } else {
+            resolve_path(name)
+        }
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority.unwrap_or(DEFAULT_PRIORITY)
+    }
+
+    fn is_glob(&self) -> bool {
+        self.source.contains(['*', '?', '['])
+    }
+
+    /// The entry's own target `name` followed by its extra `targets`
+    fn target_names<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        std::iter::once(name).chain(self.targets.iter().map(AsRef::as_ref))
+    }
+
+    /// Whether the entry deploys to `target`, for globs the target is inside the entry's directory
+    fn deploys_to(&self, name: &str, target: &Path) -> bool {
+        self.target_names(name).any(|name| {
+            if self.is_glob() {
+                target.parent() == Some(resolve_path(name).as_path())
+            } else {
+                self.resolve_target(name) == target
+            }
+        })
+    }
+
+    fn mode(&self) -> Result<Option<u32>> {
+        let Some(mode) = &self.mode else {
+            return Ok(None);
+        };
+        u32::from_str_radix(mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .map(Some)
+            .ok_or(anyhow!(
+                "Invalid mode {mode:?}, expected an octal string like \"0644\""
+            ))
+    }
+
+    /// The pinned `sha256` in lowercase, only single file sources can be pinned
+    fn pinned_hash(&self) -> Result<Option<Box<str>>> {
+        let Some(hash) = &self.sha256 else {
+            return Ok(None);
+        };
+        if self.is_glob() || !self.layers.is_empty() {
+            return Err(anyhow!("Can not use `sha256` with globs or layers"));
+        }
+        if hash.len() != 64 || !hash.chars().all(|char| char.is_ascii_hexdigit()) {
+            return Err(anyhow!(
+                "Invalid sha256 {hash:?}, expected 64 hexadecimal characters"
+            ));
+        }
+        Ok(Some(hash.to_ascii_lowercase().into()))
+    }
+
+    /// Errors unless `source` matches the pinned `sha256`, if any
+    fn verify_source(&self, source: &Path) -> Result<()> {
+        let Some(expected) = self.pinned_hash()? else {
+            return Ok(());
+        };
+        if source.is_dir() {
+            return Err(anyhow!(
+                "Can not use `sha256` with the directory {}",
+                source.display()
+            ));
+        }
+        let content = std::fs::read(source).context(format!("reading {}", source.display()))?;
+        let found = format!("{:x}", Sha256::digest(&content));
+        if *found != *expected {
+            return Err(anyhow!(
+                "Source {} does not match its pinned sha256 (expected {expected}, found {found})",
+                source.display()
+            ));
+        }
+        Ok(())
+    }
+
+    /// The `owner:group` argument to `chown`, `None` when neither is set
+    fn ownership(&self) -> Result<Option<Box<str>>> {
+        if self.owner.is_none() && self.group.is_none() {
+            return Ok(None);
+        }
+        if !self.sudo {
+            return Err(anyhow!(
+                "`owner` and `group` can only be used with `sudo = true`"
+            ));
+        }
+        for name in self.owner.iter().chain(self.group.iter()) {
+            if name.is_empty() || name.contains(|c: char| c == ':' || c.is_whitespace()) {
+                return Err(anyhow!("Invalid user or group name {name:?}"));
+            }
+        }
+        let owner = self.owner.as_deref().unwrap_or_default();
+        Ok(Some(match &self.group {
+            Some(group) => format!("{owner}:{group}").into(),
+            None => owner.into(),
+        }))
+    }
+
+    /// Whether `mode`, `owner` or `group` differ from what `old` was deployed with, or the
+    /// target's permissions drifted away from `mode` since
+    fn metadata_changed(&self, old: Option<&File>, target: &Path) -> Result<bool> {
+        if old.is_some_and(|old| {
+            (&old.mode, &old.owner, &old.group) != (&self.mode, &self.owner, &self.group)
+        }) {
+            return Ok(true);
+        }
+        let Some(mode) = self.mode()? else {
+            return Ok(false);
+        };
+        let current = std::fs::metadata(target)?.permissions().mode() & 0o7777;
+        Ok(current != mode)
+    }
+
+    /// Errors when the resolved source can not be read, so a typo fails before anything is run
+    fn check_source(&self, name: &str, source: &Path) -> Result<()> {
+        if !self.layers.is_empty() {
+            return Ok(());
+        }
+        let readable = if source.is_dir() {
+            std::fs::read_dir(source).map(drop)
+        } else {
+            std::fs::File::open(source).map(drop)
+        };
+        readable.context(format!(
+            "Source of file {name} can not be read at {}",
+            source.display()
+        ))
+    }
+
+    fn is_template(&self, source: &Path) -> bool {
+        self.template
+            .unwrap_or_else(|| source.extension().is_some_and(|ext| ext == "tera"))
+    }
+
+    /// The file name a glob match deploys under, without the `.tera` extension of templates
+    /// when `strip` is set
+    fn output_name(&self, source: &Path, strip: bool) -> String {
+        let name = source.file_name().unwrap_or_default().to_string_lossy();
+        match name.strip_suffix(".tera") {
+            Some(stem) if strip && !stem.is_empty() && !self.link && self.is_template(source) => {
+                stem.into()
+            }
+            _ => name.into(),
         }
     }
 }
 
+/// A file entry with a glob source expanded, one for every target it deploys to
+struct Deployment<'a> {
+    /// Key of the entry in `files`
+    key: &'a str,
+    /// Target as written in the config, with the matched file name appended for globs
+    name: Box<str>,
+    file: File,
+}
+
+impl Deployment<'_> {
+    fn resolve(&self) -> (&Self, PathBuf, PathBuf) {
+        let target = self.file.resolve_target(&self.name);
+        (self, resolve_path(&self.file.source), target)
+    }
+}
+
 impl From<Box<str>> for File {
     fn from(value: Box<str>) -> Self {
         Self {
@@ -116,7 +534,101 @@ impl From<Box<str>> for File {
 #[serde(deny_unknown_fields)]
 pub struct Hook {
     pub command: Box<str>,
-    pub priority: u8,
+    /// `default_priority` from `[dotty]` when unset
+    pub priority: Option<i32>,
+    /// Disabled hooks are never run and keep their state, so re-enabling a `once` hook only
+    /// runs it when it has not run with its command yet
+    pub enabled: bool,
+    /// Managers or hooks this hook has to run before
+    pub before: Vec<Box<str>>,
+    /// Managers or hooks this hook has to run after
+    pub after: Vec<Box<str>>,
+    /// Directory to run the command in instead of the current one
+    pub cwd: Option<Box<str>>,
+    /// Extra environment variables for the command
+    pub env: HashMap<Box<str>, Box<str>>,
+    /// Seconds the command may run before it is killed
+    pub timeout: Option<u64>,
+    /// Run the command as root
+    pub sudo: bool,
+    /// Only run the hook when this command succeeds
+    pub when: Option<Box<str>>,
+    /// Only run the hook when this command fails
+    pub unless: Option<Box<str>>,
+    /// Update hooks only run when at least this long passed since their last successful run,
+    /// like `12h` or `1w`
+    pub min_interval: Option<Box<str>>,
+}
+
+impl Hook {
+    pub fn priority(&self) -> i32 {
+        self.priority.unwrap_or(DEFAULT_PRIORITY)
+    }
+
+    fn min_interval(&self) -> Result<Option<Duration>> {
+        self.min_interval.as_deref().map(parse_interval).transpose()
+    }
+
+    fn predicate(&self) -> Predicate {
+        Predicate {
+            when: self.when.clone(),
+            unless: self.unless.clone(),
+        }
+    }
+}
+
+/// Commands deciding whether a hook runs, checked right before it would run
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Predicate {
+    pub when: Option<Box<str>>,
+    pub unless: Option<Box<str>>,
+}
+
+impl Predicate {
+    /// How the predicate is shown after the command, empty when there is none
+    fn suffix(&self) -> String {
+        let when = self.when.iter().map(|when| format!(" (when `{when}`)"));
+        let unless = self
+            .unless
+            .iter()
+            .map(|unless| format!(" (unless `{unless}`)"));
+        when.chain(unless).collect()
+    }
+
+    /// Why the command should not run, if it should not. The predicate commands run in the same
+    /// place as the hook, without sudo and with their output hidden
+    fn skip_reason(
+        &self,
+        shell: &Shell,
+        cwd: Option<&Path>,
+        env: &HashMap<Box<str>, Box<str>>,
+    ) -> Result<Option<String>> {
+        let succeeds = |command: &str| -> Result<bool> {
+            let mut process = shell.command(command)?;
+            process
+                .envs(env.iter().map(|(key, value)| (&**key, &**value)))
+                .stdout(Stdio::null())
+                .stderr(Stdio::null());
+            if let Some(cwd) = cwd {
+                process.current_dir(cwd);
+            }
+            let status = process
+                .status()
+                .context(format!("Could not run `{command}`"))?;
+            Ok(status.success())
+        };
+        if let Some(when) = &self.when {
+            if !succeeds(when)? {
+                return Ok(Some(format!("`when` command `{when}` failed")));
+            }
+        }
+        if let Some(unless) = &self.unless {
+            if succeeds(unless)? {
+                return Ok(Some(format!("`unless` command `{unless}` succeeded")));
+            }
+        }
+        Ok(None)
+    }
 }
 
 impl From<Box<str>> for Hook {
@@ -132,312 +644,2808 @@ impl Default for Hook {
     fn default() -> Self {
         Self {
             command: "".into(),
-            priority: 50,
+            priority: None,
+            enabled: true,
+            before: Vec::new(),
+            after: Vec::new(),
+            cwd: None,
+            env: HashMap::new(),
+            timeout: None,
+            sudo: false,
+            when: None,
+            unless: None,
+            min_interval: None,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
-#[serde(default)]
+/// A package, optionally pinned to a version with `name@version` or a table
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone)]
 #[serde(deny_unknown_fields)]
-pub struct DottyConfig {}
+pub struct Package {
+    pub name: Box<str>,
+    #[serde(default)]
+    pub version: Option<Box<str>>,
+    /// Extra arguments appended to the manager's `add` command for only this package
+    #[serde(default)]
+    pub args: Option<Box<str>>,
+    /// Installed on its own, and failing to install it only warns instead of failing the apply
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
+}
+
+/// Parses `name@version`, where a leading `@` is part of the name as in `@scope/pkg@1.2`
+impl TryFrom<Box<str>> for Package {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Box<str>) -> Result<Self> {
+        let (name, version) = match value.rsplit_once('@') {
+            Some((name, version)) if !name.is_empty() => (name.into(), Some(version.into())),
+            _ => (value.clone(), None),
+        };
+        if name.trim_start_matches('@').is_empty() || version.as_deref() == Some("") {
+            return Err(anyhow!(
+                "Package `{value}` needs a name, and a version after the `@` if it has one"
+            ));
+        }
+        Ok(Self {
+            name,
+            version,
+            args: None,
+            optional: false,
+        })
+    }
+}
+
+impl std::fmt::Display for Package {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{}@{}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl Package {
+    /// Replaces `#:?` with the name and `#:v?` with the version, empty when unpinned
+    fn substitute(&self, command: &str) -> String {
+        command
+            .replace("#:v?", self.version.as_deref().unwrap_or(""))
+            .replace("#:?", &self.name)
+    }
+}
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 #[serde(default)]
 #[serde(deny_unknown_fields)]
-pub struct Manager {
-    pub add: Option<Box<str>>,
-    pub remove: Option<Box<str>>,
-    pub update: Option<Box<str>>,
-    pub sudo: bool,
-    pub seperator: Box<str>,
-    pub priority: u8,
+pub struct DottyConfig {
+    /// Existing targets are copied here before being overwritten
+    backup_dir: Option<Box<str>>,
+    /// When disabled, undefined variables in templates render as empty strings
+    strict_templates: bool,
+    /// Same as `merge = "error"`, kept for older configs
+    strict_merge: bool,
+    /// What happens when modules define the same entry, only read from the root config
+    merge: MergePolicy,
+    sudo: SudoStrategy,
+    /// Globs of templates that every template can `include` or `import` by file name
+    template_includes: Vec<Box<str>>,
+    /// Successful applies and updates are appended here, one JSON line each
+    history_file: Option<Box<str>>,
+    /// Hash of the config the state was written from, only set in the state
+    config_hash: Option<Box<str>>,
+    /// Error on undefined environment variables in commands instead of leaving them to the shell
+    strict_env: bool,
+    /// Like `exclude` on files, but for every directory copied without sudo
+    exclude: Vec<Box<str>>,
+    /// Priority of managers, hooks and files without their own
+    default_priority: i32,
+    /// Separator of managers without their own
+    default_separator: Box<str>,
+    /// Executable files in here are run on every apply, in file name order
+    script_dir: Option<Box<str>>,
+    shell: Shell,
+    /// Read every written file back and compare it with what was written
+    verify: bool,
+    /// Drop the `.tera` extension of templates matched by glob sources from their target names
+    strip_template_ext: bool,
+    /// How package names are normalized before comparing them, for managers without their own
+    normalize_packages: Vec<Normalization>,
+    /// Install packages under their normalized name instead of the name written in the config
+    install_normalized: bool,
 }
 
-impl Default for Manager {
+impl Default for DottyConfig {
     fn default() -> Self {
         Self {
-            add: None,
-            remove: None,
-            update: None,
-            sudo: false,
-            seperator: " ".into(),
-            priority: 50,
+            backup_dir: None,
+            strict_templates: true,
+            strict_merge: false,
+            merge: MergePolicy::default(),
+            sudo: SudoStrategy::default(),
+            template_includes: Vec::new(),
+            history_file: None,
+            config_hash: None,
+            strict_env: false,
+            exclude: Vec::new(),
+            default_priority: DEFAULT_PRIORITY,
+            default_separator: DEFAULT_SEPARATOR.into(),
+            script_dir: None,
+            shell: Shell::default(),
+            verify: false,
+            strip_template_ext: false,
+            normalize_packages: Vec::new(),
+            install_normalized: false,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default)]
-#[serde(deny_unknown_fields)]
-#[serde(transparent)]
-struct TemplateContext(HashMap<Box<str>, TemplateValue>);
+/// Packages `Config::reconcile` added to or removed from the state for one manager
+#[derive(Debug)]
+pub struct Reconciled {
+    pub manager: Box<str>,
+    /// In the config and already installed
+    pub added: Vec<Package>,
+    /// In the state but no longer installed
+    pub removed: Vec<Package>,
+}
 
-#[derive(Serialize, Deserialize, Debug, Eq)]
-#[serde(deny_unknown_fields)]
-#[serde(untagged)]
-enum TemplateValue {
-    Value(Box<str>),
-    Mapping(HashMap<Box<str>, TemplateValue>),
-    Sequence(Vec<TemplateValue>),
+/// Package managers `dotty init` can write a starting config for
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    Pacman,
+    Apt,
+    Brew,
+    /// No managers or packages
+    Minimal,
 }
 
-impl PartialEq for TemplateValue {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Self::Value(a), Self::Value(b)) => a == b,
-            (Self::Mapping(a), Self::Mapping(b)) => a == b,
-            (Self::Sequence(a), Self::Sequence(b)) => {
-                a.len() == b.len() && a.iter().all(|e| b.contains(e))
-            }
-            _ => false,
+impl Preset {
+    /// Picks the preset for the first package manager found on the `PATH`, minimal if there is none
+    pub fn detect() -> Self {
+        [
+            (Self::Pacman, "pacman"),
+            (Self::Apt, "apt-get"),
+            (Self::Brew, "brew"),
+        ]
+        .into_iter()
+        .find(|(_, binary)| host::has_binary(binary))
+        .map_or(Self::Minimal, |(preset, _)| preset)
+    }
+}
+
+/// A step applied to package names before they are compared, so names that only differ in it
+/// count as the same package
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    Lowercase,
+    /// Strips surrounding whitespace
+    Trim,
+}
+
+impl Normalization {
+    /// The package with every step applied to its name, the version and arguments are kept
+    fn normalize(steps: &[Self], package: &Package) -> Package {
+        let mut name = package.name.to_string();
+        for step in steps {
+            name = match step {
+                Self::Lowercase => name.to_lowercase(),
+                Self::Trim => name.trim().into(),
+            };
+        }
+        Package {
+            name: name.into(),
+            ..package.clone()
         }
     }
 }
 
-impl TemplateValue {
-    fn combine(&mut self, other: TemplateValue) -> Result<()> {
-        match (self, other) {
-            (Self::Value(b), Self::Value(a)) => {
-                return Err(anyhow!("Duplicate value in tempalte {a} and {b}"))
-            }
-            (Self::Sequence(me), Self::Sequence(other)) => me.extend(other),
-            (Self::Mapping(me), Self::Mapping(other)) => {
+/// Which packages `Config::query_packages` returns
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageQuery {
+    /// Every package in the config
+    List,
+    /// In the config but not the state, installed by the next apply
+    Added,
+    /// In the state but not the config, removed by the next apply unless their manager is gone
+    Orphaned,
+}
+
+/// How dotty deals with `sudo` asking for a password
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SudoStrategy {
+    /// Leave it to every `sudo` invocation
+    #[default]
+    Prompt,
+    /// Authenticate once before the first sudo action and keep the timestamp fresh
+    Refresh,
+}
+
+/// How entries defined by several modules are combined
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergePolicy {
+    /// The entry loaded last wins, with a warning
+    #[default]
+    Override,
+    /// Defining an entry differently in two modules is an error
+    Error,
+    /// The entry loaded first wins, so the root config beats its modules
+    FirstWins,
+}
+
+impl MergePolicy {
+    /// Adds the entries of `other` to `current`, keeping the current one on a collision with
+    /// `FirstWins`
+    fn merge<V>(self, current: &mut HashMap<Box<str>, V>, other: HashMap<Box<str>, V>) {
+        match self {
+            Self::FirstWins => {
                 for (key, value) in other {
-                    if let Some(current) = me.get_mut(&key) {
-                        current.combine(value).context(format!("in {key}"))?;
-                    } else {
-                        me.insert(key, value);
-                    }
+                    current.entry(key).or_insert(value);
                 }
             }
-            (me, other) => {
-                return Err(anyhow!("Incompatible template values {me:?} and {other:?}"))
-            }
+            Self::Override | Self::Error => current.extend(other),
         }
-
-        Ok(())
     }
 }
 
-impl Config {
-    pub fn example() -> Self {
-        Self {
-            managers: HashMap::from([(
-                "pacman".into(),
-                Manager {
-                    add: Some("pacman -S #:?".into()),
-                    remove: Some("pacman -Rns #:?".into()),
-                    update: Some("pacman -Syu".into()),
-                    sudo: true,
-                    seperator: " ".into(),
-                    priority: 50,
-                },
-            )]),
-            module: Module::default(),
-            packages: HashMap::from([(
-                "pacman".into(),
-                HashSet::from(["neovim".into(), "git".into()]),
-            )]),
-            hooks: Hooks::default(),
-            dotty: DottyConfig {},
-            files: HashMap::new(),
-            template: TemplateContext::default(),
+impl DottyConfig {
+    /// `merge`, or `error` with `strict_merge`
+    fn merge_policy(&self) -> MergePolicy {
+        if self.strict_merge {
+            MergePolicy::Error
+        } else {
+            self.merge
         }
     }
 
-    pub fn combine(&mut self, other: Config) -> Result<()> {
-        self.managers.extend(other.managers);
-        self.hooks.once.extend(other.hooks.once);
-        self.hooks.update.extend(other.hooks.update);
-        self.files.extend(other.files);
-
-        for (manager, packages) in other.packages {
-            self.packages.entry(manager).or_default().extend(packages);
-        }
-
-        for (key, value) in other.template.0 {
-            if let Some(current) = self.template.0.get_mut(&key) {
-                current.combine(value)?;
-            } else {
-                self.template.0.insert(key, value);
+    /// Takes the settings this config leaves at their default from an imported module's, so the
+    /// importing config wins for everything it sets. Lists are combined.
+    fn combine(&mut self, other: Self) {
+        fn inherit<T: PartialEq>(current: &mut T, default: T, other: T) {
+            if *current == default {
+                *current = other;
             }
         }
 
-        Ok(())
+        let default = Self::default();
+        inherit(&mut self.backup_dir, default.backup_dir, other.backup_dir);
+        inherit(
+            &mut self.strict_templates,
+            default.strict_templates,
+            other.strict_templates,
+        );
+        inherit(
+            &mut self.strict_merge,
+            default.strict_merge,
+            other.strict_merge,
+        );
+        inherit(&mut self.merge, default.merge, other.merge);
+        inherit(&mut self.sudo, default.sudo, other.sudo);
+        inherit(
+            &mut self.history_file,
+            default.history_file,
+            other.history_file,
+        );
+        inherit(&mut self.strict_env, default.strict_env, other.strict_env);
+        inherit(
+            &mut self.default_priority,
+            default.default_priority,
+            other.default_priority,
+        );
+        inherit(
+            &mut self.default_separator,
+            default.default_separator,
+            other.default_separator,
+        );
+        inherit(&mut self.script_dir, default.script_dir, other.script_dir);
+        inherit(&mut self.shell, default.shell, other.shell);
+        inherit(&mut self.verify, default.verify, other.verify);
+        inherit(
+            &mut self.strip_template_ext,
+            default.strip_template_ext,
+            other.strip_template_ext,
+        );
+        inherit(
+            &mut self.normalize_packages,
+            default.normalize_packages,
+            other.normalize_packages,
+        );
+        inherit(
+            &mut self.install_normalized,
+            default.install_normalized,
+            other.install_normalized,
+        );
+        self.template_includes.extend(other.template_includes);
+        self.exclude.extend(other.exclude);
     }
 
-    pub fn load_dependencies(&mut self, directory: &Path) -> Result<()> {
-        if self.module.disable {
-            *self = Self::default();
-            return Ok(());
+    fn backup(&self, target: &Path, sudo: bool) -> Option<Action> {
+        let backup_dir = self.backup_dir.as_ref()?;
+        if std::fs::symlink_metadata(target).is_err() {
+            return None;
         }
 
-        for module in self.module.import.clone().into_iter() {
-            let path = directory.join(PathBuf::from_str(&module)?);
-            let content = std::fs::read_to_string(&path)?;
-            let mut config: Self = toml::from_str(&content)?;
-            let new_directory = path.parent().unwrap_or(directory);
-            config.load_dependencies(new_directory)?;
-            self.combine(config)?;
-        }
-        self.module = Module::default();
-        Ok(())
+        let timestamp = unix_time(SystemTime::now());
+        let relative = target.strip_prefix("/").unwrap_or(target);
+        let backup = PathBuf::from(shellexpand::tilde(backup_dir).as_ref())
+            .join(timestamp.to_string())
+            .join(relative);
+
+        Some(Action::Backup {
+            target: target.into(),
+            backup,
+            sudo,
+        })
     }
+}
 
-    pub fn update(&self) -> Result<Vec<Change>> {
-        let mut changes = Vec::new();
-        let empty = HashSet::new();
-        for (name, manager) in self.managers.iter() {
-            if let Some(command) = &manager.update {
-                let command = if manager.sudo {
-                    format!("sudo {}", command)
-                } else {
-                    command.to_string()
-                };
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct Manager {
+    pub add: Option<Commands>,
+    pub remove: Option<Box<str>>,
+    pub update: Option<Box<str>>,
+    /// Prints the installed packages one per line, each line starting with the package name
+    pub list: Option<Box<str>>,
+    /// Run once before the manager's packages change in an apply that installs any, like
+    /// `apt-get update`
+    pub refresh: Option<Box<str>>,
+    pub sudo: bool,
+    /// `default_separator` from `[dotty]` when unset
+    #[serde(alias = "seperator")]
+    pub separator: Option<Box<str>>,
+    /// `normalize_packages` from `[dotty]` when unset
+    pub normalize: Option<Vec<Normalization>>,
+    /// `default_priority` from `[dotty]` when unset
+    pub priority: Option<i32>,
+    /// Managers or hooks this manager has to run before
+    pub before: Vec<Box<str>>,
+    /// Managers or hooks this manager has to run after
+    pub after: Vec<Box<str>>,
+    /// Seconds a command may run before it is killed
+    pub timeout: Option<u64>,
+    /// Times a failing add or update command is retried, `--retries` when unset
+    pub retries: Option<u32>,
+}
 
-                let packages = self.packages.get(name).unwrap_or(&empty);
+/// A single command, or several run in order where each gets the `#:?` substitution.
+/// Steps without the placeholder run once, like enabling a repository before installing
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(untagged)]
+pub enum Commands {
+    Single(Box<str>),
+    Sequence(Vec<Box<str>>),
+}
 
-                if !manager.seperator.is_empty() {
-                    let joined = packages
+impl Commands {
+    pub fn steps(&self) -> impl Iterator<Item = &str> {
+        let steps = match self {
+            Self::Single(command) => std::slice::from_ref(command),
+            Self::Sequence(commands) => commands.as_slice(),
+        };
+        steps.iter().map(AsRef::as_ref)
+    }
+}
+
+impl From<&str> for Commands {
+    fn from(command: &str) -> Self {
+        Self::Single(command.into())
+    }
+}
+
+impl Manager {
+    pub fn priority(&self) -> i32 {
+        self.priority.unwrap_or(DEFAULT_PRIORITY)
+    }
+
+    pub fn separator(&self) -> &str {
+        self.separator.as_deref().unwrap_or(DEFAULT_SEPARATOR)
+    }
+
+    /// Checks the `#:?` placeholder is used correctly, `update` is allowed to leave it out
+    fn validate(&self, name: &str) -> Result<()> {
+        let add = self
+            .add
+            .iter()
+            .flat_map(Commands::steps)
+            .collect::<Vec<_>>();
+        let commands = [
+            ("add", add, true),
+            ("remove", self.remove.as_deref().into_iter().collect(), true),
+            (
+                "update",
+                self.update.as_deref().into_iter().collect(),
+                false,
+            ),
+        ];
+        for (field, steps, required) in commands {
+            if steps.is_empty() {
+                continue;
+            }
+
+            // With several steps only one of them has to take the packages
+            let counts = steps.iter().map(|step| step.matches("#:?").count());
+            if counts.clone().sum::<usize>() == 0 && required {
+                return Err(anyhow!(
+                    "Manager {name}: `{field}` is missing the `#:?` placeholder"
+                ));
+            }
+            if counts.max().unwrap_or(0) > 1 && !self.separator().is_empty() {
+                return Err(anyhow!(
+                    "Manager {name}: `{field}` must contain `#:?` exactly once when `separator` is set"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default, Clone)]
+#[serde(deny_unknown_fields)]
+#[serde(transparent)]
+struct TemplateContext(HashMap<Box<str>, TemplateValue>);
+
+/// Sequences compare in order, since reordering one changes what templates render
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+#[serde(untagged)]
+enum TemplateValue {
+    Value(Box<str>),
+    Boolean(bool),
+    /// Before `Float`, so whole numbers stay integers
+    Integer(i64),
+    Float(TemplateFloat),
+    Mapping(HashMap<Box<str>, TemplateValue>),
+    Sequence(Vec<TemplateValue>),
+}
+
+/// Compared by bits so template values, and with them configs, can be `Eq`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(transparent)]
+struct TemplateFloat(f64);
+
+impl PartialEq for TemplateFloat {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
+
+impl Eq for TemplateFloat {}
+
+/// Template key holding the environment variables
+const ENV_TEMPLATE_KEY: &str = "env";
+/// Template key holding the values of `[secrets]`
+const SECRETS_TEMPLATE_KEY: &str = "secrets";
+/// Key of a template mapping that is replaced by the contents of the TOML or JSON file it names
+const FILE_TEMPLATE_KEY: &str = "$file";
+
+impl TemplateContext {
+    fn context(&self) -> Result<tera::Context> {
+        if self.0.contains_key(ENV_TEMPLATE_KEY) {
+            return Err(anyhow!(
+                "`template.{ENV_TEMPLATE_KEY}` is reserved for environment variables"
+            ));
+        }
+
+        let mut context = tera::Context::from_serialize(self)?;
+        let env = std::env::vars().collect::<HashMap<_, _>>();
+        context.insert(ENV_TEMPLATE_KEY, &env);
+        Ok(context)
+    }
+
+    fn load_files(&mut self, directory: &Path) -> Result<()> {
+        for (key, value) in self.0.iter_mut() {
+            value
+                .load_files(directory, &mut Vec::new())
+                .context(format!("in template {key}"))?;
+        }
+        Ok(())
+    }
+}
+
+impl TemplateValue {
+    /// `loading` holds the chain of data files leading here to detect files referring to
+    /// themselves
+    fn load_files(&mut self, directory: &Path, loading: &mut Vec<PathBuf>) -> Result<()> {
+        match self {
+            Self::Value(_) | Self::Boolean(_) | Self::Integer(_) | Self::Float(_) => {}
+            Self::Mapping(values) if values.contains_key(FILE_TEMPLATE_KEY) => {
+                let (1, Some(Self::Value(file))) = (values.len(), values.get(FILE_TEMPLATE_KEY))
+                else {
+                    return Err(anyhow!(
+                        "`{FILE_TEMPLATE_KEY}` takes a path and can not have other keys next to it"
+                    ));
+                };
+                let path = directory.join(shellexpand::tilde(file).as_ref());
+                let content = std::fs::read_to_string(&path)
+                    .context(format!("Template data {} not found", path.display()))?;
+                let path = path.canonicalize()?;
+                if let Some(start) = loading.iter().position(|parent| *parent == path) {
+                    let cycle = loading[start..]
+                        .iter()
+                        .chain([&path])
+                        .map(|file| file.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(" -> ");
+                    return Err(anyhow!("Template data refers back to itself: {cycle}"));
+                }
+                *self = match path.extension().and_then(|ext| ext.to_str()) {
+                    Some("toml") => toml::from_str(&content)?,
+                    Some("json") => serde_json::from_str(&content)?,
+                    _ => {
+                        return Err(anyhow!(
+                            "Unsupported template data format for {}",
+                            path.display()
+                        ))
+                    }
+                };
+                // The file might in turn refer to other files
+                loading.push(path.clone());
+                self.load_files(path.parent().unwrap_or(directory), loading)?;
+                loading.pop();
+            }
+            Self::Mapping(values) => {
+                for value in values.values_mut() {
+                    value.load_files(directory, loading)?;
+                }
+            }
+            Self::Sequence(values) => {
+                for value in values.iter_mut() {
+                    value.load_files(directory, loading)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_scalar(&self) -> bool {
+        matches!(
+            self,
+            Self::Value(_) | Self::Boolean(_) | Self::Integer(_) | Self::Float(_)
+        )
+    }
+
+    /// Keys of the scalars `other` sets to a different value than this one, which `combine`
+    /// has to pick between
+    fn conflicts(&self, other: &TemplateValue, key: &str, found: &mut Vec<String>) {
+        match (self, other) {
+            (me, other) if me.is_scalar() && other.is_scalar() && me != other => {
+                found.push(key.into());
+            }
+            (Self::Mapping(me), Self::Mapping(other)) => {
+                for (name, value) in other {
+                    if let Some(current) = me.get(name) {
+                        current.conflicts(value, &format!("{key}.{name}"), found);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Merges `other` from a later module into this value, sequences are combined while
+    /// duplicate scalars are resolved by `policy`
+    fn combine(&mut self, other: TemplateValue, policy: MergePolicy) -> Result<()> {
+        match (self, other) {
+            (me, other) if me.is_scalar() && other.is_scalar() => match policy {
+                MergePolicy::Override => *me = other,
+                MergePolicy::FirstWins => {}
+                MergePolicy::Error => {
+                    return Err(anyhow!("Duplicate value in template {other:?} and {me:?}"))
+                }
+            },
+            (Self::Sequence(me), Self::Sequence(other)) => me.extend(other),
+            (Self::Mapping(me), Self::Mapping(other)) => {
+                for (key, value) in other {
+                    if let Some(current) = me.get_mut(&key) {
+                        current
+                            .combine(value, policy)
+                            .context(format!("in {key}"))?;
+                    } else {
+                        me.insert(key, value);
+                    }
+                }
+            }
+            (me, other) => {
+                return Err(anyhow!("Incompatible template values {me:?} and {other:?}"))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Config {
+    /// A starting config using the preset's package manager
+    pub fn preset(preset: Preset) -> Self {
+        let (name, add, remove, update, list, sudo) = match preset {
+            Preset::Minimal => return Self::default(),
+            Preset::Pacman => (
+                "pacman",
+                "pacman -S #:?",
+                "pacman -Rns #:?",
+                "pacman -Syu",
+                "pacman -Qq",
+                true,
+            ),
+            Preset::Apt => (
+                "apt",
+                "apt-get install -y #:?",
+                "apt-get remove -y #:?",
+                "sh -c 'apt-get update && apt-get upgrade -y'",
+                "apt-mark showmanual",
+                true,
+            ),
+            Preset::Brew => (
+                "brew",
+                "brew install #:?",
+                "brew uninstall #:?",
+                "brew upgrade",
+                "brew list -1",
+                false,
+            ),
+        };
+
+        let manager = Manager {
+            add: Some(add.into()),
+            remove: Some(remove.into()),
+            update: Some(update.into()),
+            list: Some(list.into()),
+            refresh: matches!(preset, Preset::Apt).then(|| "apt-get update".into()),
+            sudo,
+            ..Manager::default()
+        };
+        Self {
+            managers: HashMap::from([(name.into(), manager)]),
+            packages: HashMap::from([(
+                name.into(),
+                HashSet::from([
+                    SupportsShorthand(Package::try_from(Box::from("neovim")).unwrap()),
+                    SupportsShorthand(Package::try_from(Box::from("git")).unwrap()),
+                ]),
+            )]),
+            ..Self::default()
+        }
+    }
+
+    /// Adds the entries of `other` to this config, `policy` decides which of two entries with
+    /// the same name is kept. Packages are always combined
+    pub fn combine(&mut self, other: Config, policy: MergePolicy) -> Result<()> {
+        policy.merge(&mut self.managers, other.managers);
+        policy.merge(&mut self.hooks.once, other.hooks.once);
+        policy.merge(&mut self.hooks.update, other.hooks.update);
+        policy.merge(&mut self.files, other.files);
+        for (name, profile) in other.profiles {
+            match self.profiles.get_mut(&name) {
+                Some(current) => current.combine(profile, policy),
+                None => {
+                    self.profiles.insert(name, profile);
+                }
+            }
+        }
+        policy.merge(&mut self.origins, other.origins);
+        self.git_modules.extend(other.git_modules);
+        // `module` is left out since the imports of `other` are already loaded
+        self.dotty.combine(other.dotty);
+
+        for (manager, packages) in other.packages {
+            self.packages.entry(manager).or_default().extend(packages);
+        }
+        policy.merge(&mut self.secrets, other.secrets);
+        for (alias, names) in other.aliases {
+            self.aliases.entry(alias).or_default().extend(names);
+        }
+        self.shared_packages.extend(other.shared_packages);
+
+        for (key, value) in other.template.0 {
+            if let Some(current) = self.template.0.get_mut(&key) {
+                current
+                    .combine(value, policy)
+                    .context(format!("in template.{key}"))?;
+            } else {
+                self.template.0.insert(key, value);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Expands glob sources of enabled files into one deployment per matched file
+    fn deployments(&self) -> Result<Vec<Deployment<'_>>> {
+        let mut deployments = Vec::with_capacity(self.files.len());
+        for (key, file) in self.files.iter() {
+            if !file.enabled || !file.is_glob() {
+                for name in file.target_names(key) {
+                    deployments.push(Deployment {
+                        key,
+                        name: name.into(),
+                        file: (**file).clone(),
+                    });
+                }
+                continue;
+            }
+
+            let pattern = shellexpand::tilde(&file.source);
+            let mut matches = globwalk::glob(&pattern)
+                .context(format!("Invalid glob {}", file.source))?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.into_path())
+                .collect::<Vec<_>>();
+            if matches.is_empty() {
+                return Err(anyhow!("Glob {} for {key} matched nothing", file.source));
+            }
+            matches.sort();
+
+            for path in matches {
+                if path.file_name().is_none() {
+                    continue;
+                }
+                let file_name = file.output_name(&path, self.dotty.strip_template_ext);
+                for name in file.target_names(key) {
+                    deployments.push(Deployment {
+                        key,
+                        name: format!("{}/{file_name}", name.trim_end_matches('/')).into(),
+                        file: File {
+                            source: path.to_string_lossy().into(),
+                            ..(**file).clone()
+                        },
+                    });
+                }
+            }
+        }
+        Ok(deployments)
+    }
+
+    fn templater(&self) -> Result<&tera::Tera> {
+        if let Some(templater) = self.templater.tera.get() {
+            return Ok(templater);
+        }
+
+        let mut includes = Vec::new();
+        for pattern in self.dotty.template_includes.iter() {
+            let expanded = shellexpand::tilde(pattern);
+            let matches = globwalk::glob(&expanded)
+                .context(format!("Invalid glob {pattern}"))?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.into_path());
+            for path in matches {
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned());
+                includes.push((path, name));
+            }
+        }
+
+        let mut templater = tera::Tera::default();
+        templater
+            .add_template_files(includes)
+            .map_err(|err| anyhow!("Invalid template include: {}", root_cause(&err)))?;
+        Ok(self.templater.tera.get_or_init(|| templater))
+    }
+
+    fn render(&self, source: &Path) -> Result<String> {
+        if let Some(rendered) = self.templater.rendered.lock().unwrap().get(source) {
+            return Ok(rendered.clone());
+        }
+        let mut context = self.template.context()?;
+        if !self.secrets.is_empty() {
+            if self.template.0.contains_key(SECRETS_TEMPLATE_KEY) {
+                return Err(anyhow!(
+                    "`template.{SECRETS_TEMPLATE_KEY}` can not be used together with [secrets]"
+                ));
+            }
+            context.insert(SECRETS_TEMPLATE_KEY, self.secrets()?);
+        }
+        let rendered = render_template(
+            self.templater()?,
+            source,
+            context,
+            self.dotty.strict_templates,
+        )?;
+        self.templater
+            .rendered
+            .lock()
+            .unwrap()
+            .insert(source.to_owned(), rendered.clone());
+        Ok(rendered)
+    }
+
+    /// Runs every `[secrets]` command once, their output is never printed
+    fn secrets(&self) -> Result<&HashMap<Box<str>, String>> {
+        if let Some(secrets) = self.templater.secrets.get() {
+            return Ok(secrets);
+        }
+
+        let mut secrets = HashMap::with_capacity(self.secrets.len());
+        for (name, secret) in self.secrets.iter() {
+            let command = expand_command(&secret.command, self.dotty.strict_env)?;
+            let output = self
+                .dotty
+                .shell
+                .command(&command)?
+                .stderr(Stdio::inherit())
+                .output()
+                .context(format!("Could not run the command of secret {name}"))?;
+            if !output.status.success() {
+                return Err(anyhow!("The command of secret {name} failed"));
+            }
+            let value = String::from_utf8(output.stdout)
+                .map_err(|_| anyhow!("The command of secret {name} did not print UTF-8"))?;
+            crate::verbosity::hide_secret(name, value.trim());
+            secrets.insert(name.clone(), value.trim().to_owned());
+        }
+        Ok(self.templater.secrets.get_or_init(|| secrets))
+    }
+
+    /// Replaces the value of every secret resolved so far with its name
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_owned();
+        for (name, value) in self.templater.secrets.get().into_iter().flatten() {
+            if !value.is_empty() {
+                text = text.replace(value.as_str(), &format!("<secret {name}>"));
+            }
+        }
+        text
+    }
+
+    pub fn history_file(&self) -> Option<PathBuf> {
+        let history_file = self.dotty.history_file.as_ref()?;
+        Some(PathBuf::from(shellexpand::tilde(history_file).as_ref()))
+    }
+
+    pub fn is_manager(&self, name: &str) -> bool {
+        self.managers.contains_key(name)
+    }
+
+    pub fn sudo_strategy(&self) -> SudoStrategy {
+        self.dotty.sudo
+    }
+
+    /// Stores the hash of every enabled file's source, so later diffs can compare contents
+    pub fn record_hashes(&mut self) -> Result<()> {
+        let mut hashes = HashMap::new();
+        for deployment in self.deployments()? {
+            if !deployment.file.enabled {
+                continue;
+            }
+            let (_, source, _) = deployment.resolve();
+            if let Some(hash) = source_hash(&deployment.file, &source)? {
+                hashes.insert(deployment.name, hash);
+            }
+        }
+        self.hashes = hashes;
+        Ok(())
+    }
+
+    /// Hash of the resolved config, leaving out what only the state records
+    pub fn content_hash(&self) -> Result<Box<str>> {
+        let mut config = self.clone();
+        config.hashes.clear();
+        config.failed_hooks.clear();
+        config.dotty.config_hash = None;
+        let mut value = serde_json::to_value(&config)?;
+        sort_arrays(&mut value);
+        Ok(format!("{:x}", Sha256::digest(value.to_string())).into())
+    }
+
+    /// Stores the hash of the config this state is written from
+    pub fn record_config_hash(&mut self, config: &Config) -> Result<()> {
+        self.dotty.config_hash = Some(config.content_hash()?);
+        Ok(())
+    }
+
+    /// Whether this state was written from a config that differs from `config`, false for states
+    /// without a stored hash
+    pub fn is_stale_for(&self, config: &Config) -> Result<bool> {
+        match &self.dotty.config_hash {
+            Some(stored) => Ok(*stored != config.content_hash()?),
+            None => Ok(false),
+        }
+    }
+
+    /// Undoes the effect of a change on this state, returning its entries to how they were in `old`
+    pub fn revert(&mut self, change: &Change, old: &Config) {
+        match change {
+            Change::AddPackage { manager, packages } => {
+                if let Some(current) = self.packages.get_mut(manager) {
+                    current.retain(|package| !packages.contains(package));
+                }
+            }
+            Change::RemovePackage { manager, packages } => {
+                self.packages.entry(manager.clone()).or_default().extend(
+                    packages
+                        .iter()
+                        .map(|package| SupportsShorthand(package.clone())),
+                );
+            }
+            Change::CopyFile(_, target) => {
+                let hashed = self
+                    .hashes
+                    .keys()
+                    .chain(old.hashes.keys())
+                    .filter(|name| resolve_path(name) == *target)
+                    .cloned()
+                    .collect::<HashSet<_>>();
+                for name in hashed {
+                    match old.hashes.get(&name) {
+                        Some(hash) => self.hashes.insert(name, hash.clone()),
+                        None => self.hashes.remove(&name),
+                    };
+                }
+
+                let names = self
+                    .files
+                    .iter()
+                    .filter(|(name, file)| file.deploys_to(name, target))
+                    .map(|(name, _)| name.clone())
+                    .collect::<Vec<_>>();
+                for name in names {
+                    match old.files.get(&name) {
+                        Some(file) => self.files.insert(name, file.clone()),
+                        None => self.files.remove(&name),
+                    };
+                }
+            }
+            Change::RemoveFile(_, target) => {
+                for (name, file) in old.files.iter() {
+                    if self.files.contains_key(name) || !file.deploys_to(name, target) {
+                        continue;
+                    }
+                    self.files.insert(name.clone(), file.clone());
+                    for (hashed, hash) in old.hashes.iter() {
+                        if file.deploys_to(name, &resolve_path(hashed)) {
+                            self.hashes.insert(hashed.clone(), hash.clone());
+                        }
+                    }
+                }
+            }
+            Change::RawCommand { command, .. } => {
+                let names = self
+                    .hooks
+                    .once
+                    .iter()
+                    .filter(|(_, hook)| hook.command == *command)
+                    .map(|(name, _)| name.clone())
+                    .collect::<Vec<_>>();
+                for name in names {
+                    match old.hooks.once.get(&name) {
+                        Some(hook) => self.hooks.once.insert(name.clone(), hook.clone()),
+                        None => self.hooks.once.remove(&name),
+                    };
+                    if old.failed_hooks.contains(&name) {
+                        self.failed_hooks.insert(name);
+                    } else {
+                        self.failed_hooks.remove(&name);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Records a change that failed, `once` hooks are kept and marked to run again while
+    /// anything else is reverted to how it was in `old`
+    pub fn record_failure(&mut self, change: &Change, old: &Config) {
+        match change {
+            Change::RawCommand { origin, .. } if self.hooks.once.contains_key(origin) => {
+                self.failed_hooks.insert(origin.clone());
+            }
+            _ => self.revert(change, old),
+        }
+    }
+
+    /// Puts back what `old` recorded for every disabled file and hook, or drops them when `old`
+    /// has none, so a disabled entry keeps its state instead of being recorded as applied
+    pub fn keep_disabled(&mut self, old: &Config) {
+        let disabled = self
+            .files
+            .iter()
+            .filter(|(_, file)| !file.enabled)
+            .map(|(name, file)| (name.clone(), (**file).clone()))
+            .collect::<Vec<_>>();
+        for (name, file) in disabled {
+            let hashed = self
+                .hashes
+                .keys()
+                .chain(old.hashes.keys())
+                .filter(|hashed| file.deploys_to(&name, &resolve_path(hashed)))
+                .cloned()
+                .collect::<HashSet<_>>();
+            for hashed in hashed {
+                match old.hashes.get(&hashed) {
+                    Some(hash) => self.hashes.insert(hashed, hash.clone()),
+                    None => self.hashes.remove(&hashed),
+                };
+            }
+            match old.files.get(&name) {
+                Some(file) => self.files.insert(name, file.clone()),
+                None => self.files.remove(&name),
+            };
+        }
+
+        for (hooks, old_hooks) in [
+            (&mut self.hooks.once, &old.hooks.once),
+            (&mut self.hooks.update, &old.hooks.update),
+        ] {
+            let disabled = hooks
+                .iter()
+                .filter(|(_, hook)| !hook.enabled)
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>();
+            for name in disabled {
+                if old.failed_hooks.contains(&name) {
+                    self.failed_hooks.insert(name.clone());
+                } else {
+                    self.failed_hooks.remove(&name);
+                }
+                match old.hook_runs.get(&name) {
+                    Some(time) => self.hook_runs.insert(name.clone(), *time),
+                    None => self.hook_runs.remove(&name),
+                };
+                match old_hooks.get(&name) {
+                    Some(hook) => hooks.insert(name, hook.clone()),
+                    None => hooks.remove(&name),
+                };
+            }
+        }
+    }
+
+    /// Sets the priority and separator of entries without their own from `[dotty]`, which is
+    /// done once every module is loaded so the defaults also reach imported entries
+    fn fill_defaults(&mut self) {
+        let priority = self.dotty.default_priority;
+        let separator = &self.dotty.default_separator;
+        for manager in self.managers.values_mut() {
+            manager.priority.get_or_insert(priority);
+            manager.separator.get_or_insert_with(|| separator.clone());
+        }
+
+        let profiles = self.profiles.values_mut();
+        let (mut files, mut hooks) = (Vec::new(), Vec::new());
+        for profile in profiles {
+            files.extend(profile.files.values_mut());
+            hooks.extend(profile.hooks.once.values_mut());
+            hooks.extend(profile.hooks.update.values_mut());
+        }
+        files.extend(self.files.values_mut());
+        hooks.extend(self.hooks.once.values_mut());
+        hooks.extend(self.hooks.update.values_mut());
+        for file in files {
+            file.priority.get_or_insert(priority);
+        }
+        for hook in hooks {
+            hook.priority.get_or_insert(priority);
+        }
+    }
+
+    /// Runs every executable file in `script_dir`, with the priority taken from a number at the
+    /// start of the file name like `10-setup.sh`
+    fn scripts(&self, changes: &mut Vec<Change>, skips: &mut Vec<Skip>) -> Result<()> {
+        let Some(script_dir) = &self.dotty.script_dir else {
+            return Ok(());
+        };
+        let directory = resolve_path(script_dir);
+        let mut entries = std::fs::read_dir(&directory)
+            .context(format!(
+                "Script directory {} can not be read",
+                directory.display()
+            ))?
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let metadata = std::fs::metadata(&path)?;
+            if !metadata.is_file() {
+                continue;
+            }
+            if metadata.permissions().mode() & 0o111 == 0 {
+                skips.push(Skip {
+                    entry: path.to_string_lossy().into(),
+                    reason: SkipReason::NotExecutable,
+                });
+                continue;
+            }
+
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let number = name
+                .chars()
+                .take_while(char::is_ascii_digit)
+                .collect::<String>();
+            let priority = match number.parse::<u64>() {
+                Ok(number) => i32::try_from(number).unwrap_or(i32::MAX),
+                Err(_) => self.dotty.default_priority,
+            };
+            changes.push(Change::RawCommand {
+                command: shell_quote(&path.to_string_lossy()).into(),
+                sudo: false,
+                priority,
+                origin: format!("{SCRIPT_ORIGIN}{name}").into(),
+                cwd: None,
+                env: HashMap::new(),
+                timeout: None,
+                retries: Some(0),
+                predicate: Predicate::default(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether the config imports any modules, conditionally or not
+    pub fn has_imports(&self) -> bool {
+        !self.module.import.is_empty() || !self.module.import_if.is_empty()
+    }
+
+    /// Every path whose changes can change what an apply does: the config with its modules, the
+    /// script directory and the sources and layers of enabled files. Globs are watched from
+    /// their first literal directory, so new matches are noticed too
+    pub fn watched_paths(&self) -> Vec<PathBuf> {
+        let mut paths = self.modules.clone();
+        paths.extend(self.dotty.script_dir.iter().map(|dir| resolve_path(dir)));
+        for file in self.files.values().filter(|file| file.enabled) {
+            if file.is_glob() {
+                let source = shellexpand::tilde(&file.source);
+                let root = Path::new(source.as_ref())
+                    .components()
+                    .take_while(|part| {
+                        !part.as_os_str().to_string_lossy().contains(['*', '?', '['])
+                    })
+                    .collect();
+                paths.push(root);
+            } else {
+                paths.push(resolve_path(&file.source));
+            }
+            paths.extend(file.layers.iter().map(|layer| resolve_path(layer)));
+        }
+        paths
+    }
+
+    /// Merges the selected profiles into the config, dropping every other profile
+    pub fn select_profiles(&mut self, selected: &[Box<str>]) -> Result<()> {
+        let mut profiles = std::mem::take(&mut self.profiles);
+        for name in selected {
+            let profile = profiles
+                .remove(name)
+                .ok_or(anyhow!("Profile {name} not found"))?;
+            self.combine(
+                Config {
+                    packages: profile.packages,
+                    hooks: profile.hooks,
+                    files: profile.files,
+                    ..Self::default()
+                },
+                MergePolicy::Override,
+            )
+            .context(format!("in profile {name}"))?;
+        }
+        Ok(())
+    }
+
+    fn normalization(&self, manager: &str) -> &[Normalization] {
+        self.managers
+            .get(manager)
+            .and_then(|manager| manager.normalize.as_deref())
+            .unwrap_or(&self.dotty.normalize_packages)
+    }
+
+    /// The packages in `packages` whose normalized name is not in `others`
+    fn package_difference<'a>(
+        &self,
+        manager: &str,
+        packages: &'a HashSet<SupportsShorthand<Package>>,
+        others: &HashSet<SupportsShorthand<Package>>,
+    ) -> impl Iterator<Item = &'a Package> + 'a {
+        let steps = self.normalization(manager).to_vec();
+        let others = others
+            .iter()
+            .map(|package| Normalization::normalize(&steps, package))
+            .collect::<HashSet<_>>();
+        packages
+            .iter()
+            .map(|package| &**package)
+            .filter(move |package| !others.contains(&Normalization::normalize(&steps, package)))
+    }
+
+    /// Collapses the packages of each manager that are the same after normalization into one,
+    /// the first name in sorted order or the normalized one with `install_normalized`
+    pub fn normalize_packages(&mut self) {
+        let managers = self.packages.keys().cloned().collect::<Vec<_>>();
+        for manager in managers {
+            let steps = self.normalization(&manager).to_vec();
+            if steps.is_empty() {
+                continue;
+            }
+            let mut kept: BTreeMap<Package, Package> = BTreeMap::new();
+            for package in self.packages[&manager].iter() {
+                let normalized = Normalization::normalize(&steps, package);
+                let name = if self.dotty.install_normalized {
+                    normalized.clone()
+                } else {
+                    (**package).clone()
+                };
+                kept.entry(normalized)
+                    .and_modify(|current| *current = current.clone().min(name.clone()))
+                    .or_insert(name);
+            }
+            self.packages
+                .insert(manager, kept.into_values().map(SupportsShorthand).collect());
+        }
+    }
+
+    /// Adds every shared package to the managers that have a name for it, warning about the ones
+    /// no manager of this config has a name for
+    pub fn resolve_aliases(&mut self) {
+        let mut shared = self.shared_packages.iter().collect::<Vec<_>>();
+        shared.sort_by(|a, b| a.name.cmp(&b.name));
+        for package in shared {
+            let names = self.aliases.get(&package.name);
+            let mut resolved = false;
+            for (manager, name) in names.into_iter().flatten() {
+                if !self.managers.contains_key(manager) {
+                    continue;
+                }
+                let concrete = Package {
+                    name: name.clone(),
+                    ..(**package).clone()
+                };
+                self.packages
+                    .entry(manager.clone())
+                    .or_default()
+                    .insert(SupportsShorthand(concrete));
+                resolved = true;
+            }
+            if !resolved {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "Warning: skipping {}, none of the managers have a name for it in [aliases]",
+                        package.name
+                    )
+                    .yellow()
+                );
+            }
+        }
+    }
+
+    fn apply_removals(&mut self, removals: &Removals) {
+        for manager in removals.managers.iter() {
+            self.managers.remove(manager);
+            self.packages.remove(manager);
+        }
+        for (manager, names) in removals.packages.iter() {
+            if let Some(packages) = self.packages.get_mut(manager) {
+                packages.retain(|package| !names.contains(&package.name));
+            }
+        }
+        for hook in removals.hooks.iter() {
+            self.hooks.once.remove(hook);
+            self.hooks.update.remove(hook);
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        match self.problems().into_iter().next() {
+            Some(problem) => Err(problem),
+            None => Ok(()),
+        }
+    }
+
+    /// Every problem `validate` looks for, rather than only the first one
+    fn problems(&self) -> Vec<anyhow::Error> {
+        let mut problems = Vec::new();
+        for (name, manager) in self.managers.iter() {
+            problems.extend(manager.validate(name).err());
+        }
+        for (name, file) in self.files.iter() {
+            problems.extend(file.mode().context(format!("in file {name}")).err());
+            problems.extend(file.ownership().context(format!("in file {name}")).err());
+            problems.extend(file.pinned_hash().context(format!("in file {name}")).err());
+            problems.extend(
+                exclusions(&file.exclude)
+                    .context(format!("in file {name}"))
+                    .err(),
+            );
+        }
+        problems.extend(exclusions(&self.dotty.exclude).err());
+        problems.extend(self.dotty.shell.check().err());
+        for (name, hook) in self.hooks.update.iter() {
+            problems.extend(hook.min_interval().context(format!("in hook {name}")).err());
+        }
+        for (name, hook) in self.hooks.once.iter() {
+            if hook.min_interval.is_some() {
+                problems.push(anyhow!(
+                    "Hook {name}: `min_interval` only applies to update hooks"
+                ));
+            }
+        }
+
+        let successors = self.successors();
+        let known = |name: &str| {
+            self.managers.contains_key(name)
+                || self.hooks.once.contains_key(name)
+                || self.hooks.update.contains_key(name)
+        };
+        for (name, others) in successors.iter() {
+            if let Some(unknown) = std::iter::once(name).chain(others).find(|x| !known(x)) {
+                problems.push(anyhow!(
+                    "`before`/`after` refers to {unknown}, which is not a manager or hook"
+                ));
+            }
+            if runs_before(&successors, name, name) {
+                problems.push(anyhow!("{name} is part of a `before`/`after` cycle"));
+            }
+        }
+        problems
+    }
+
+    /// Like `validate` but collects every problem, and also checks what would otherwise only
+    /// fail during an apply: missing sources, conflicting targets and templates not parsing
+    pub fn check(&self) -> Vec<anyhow::Error> {
+        let mut problems = self.problems();
+        problems.extend(self.template.context().err());
+
+        let deployments = match self.deployments() {
+            Ok(deployments) => deployments,
+            Err(err) => {
+                problems.push(err);
+                return problems;
+            }
+        };
+        let resolved = deployments
+            .iter()
+            .filter(|deployment| deployment.file.enabled)
+            .map(|deployment| deployment.resolve())
+            .collect::<Vec<_>>();
+        problems.extend(find_overridden(&resolved).err());
+
+        for (deployment, source, _) in resolved {
+            let (name, file) = (&deployment.name, &deployment.file);
+            if !file.layers.is_empty() {
+                for layer in file.layers.iter() {
+                    if !resolve_path(layer).exists() {
+                        problems.push(anyhow!("Layer {layer} of file {name} does not exist"));
+                    }
+                }
+            } else if !source.exists() {
+                problems.push(anyhow!(
+                    "Source {} of file {name} does not exist",
+                    file.source
+                ));
+            } else if !file.link && file.is_template(&source) {
+                match self.templater() {
+                    Ok(templater) => {
+                        let parsed = templater.clone().add_template_file(&source, None);
+                        if let Err(err) = parsed {
+                            problems.push(anyhow!(
+                                "Invalid template {}: {}",
+                                source.display(),
+                                root_cause(&err)
+                            ));
+                        }
+                    }
+                    Err(err) => problems.push(err),
+                }
+            }
+        }
+        problems
+    }
+
+    /// Checks the host can apply the config: the shell and the programs managers run are
+    /// installed, every source can be read, every template parses and sudo is there when needed
+    pub fn diagnose(&self) -> Vec<Diagnosis> {
+        let mut diagnoses = vec![Diagnosis::new(
+            "Shell is installed",
+            self.dotty.shell.check(),
+        )];
+
+        let mut managers = self.managers.iter().collect::<Vec<_>>();
+        managers.sort_by_key(|(name, _)| *name);
+        for (name, manager) in managers {
+            let commands = [
+                &manager.remove,
+                &manager.update,
+                &manager.list,
+                &manager.refresh,
+            ];
+            let mut programs = commands
+                .into_iter()
+                .flatten()
+                .map(AsRef::as_ref)
+                .chain(manager.add.iter().flat_map(Commands::steps))
+                .filter_map(command_program)
+                .collect::<Vec<_>>();
+            programs.sort();
+            programs.dedup();
+            for program in programs {
+                let found = host::has_binary(program) || Path::new(program).is_file();
+                diagnoses.push(Diagnosis::new(
+                    format!("Manager {name} can run {program}"),
+                    found
+                        .then_some(())
+                        .ok_or(anyhow!("{program} is not installed")),
+                ));
+            }
+        }
+
+        match self.deployments() {
+            Ok(deployments) => {
+                for deployment in deployments
+                    .iter()
+                    .filter(|deployment| deployment.file.enabled)
+                {
+                    let (deployment, source, _) = deployment.resolve();
+                    let (name, file) = (&deployment.name, &deployment.file);
+                    let readable = file.check_source(name, &source);
+                    let readable_ok = readable.is_ok();
+                    diagnoses.push(Diagnosis::new(
+                        format!("Source of file {name} can be read"),
+                        readable,
+                    ));
+                    if readable_ok && !file.link && file.is_template(&source) {
+                        let parsed = self.templater().and_then(|templater| {
+                            templater
+                                .clone()
+                                .add_template_file(&source, None)
+                                .map(drop)
+                                .map_err(|err| anyhow!("{}", root_cause(&err)))
+                        });
+                        diagnoses.push(Diagnosis::new(
+                            format!("Template {} parses", source.display()),
+                            parsed,
+                        ));
+                    }
+                }
+            }
+            Err(err) => diagnoses.push(Diagnosis::new("Every glob source matches", Err(err))),
+        }
+
+        if self.uses_sudo() {
+            let found = host::has_binary("sudo");
+            diagnoses.push(Diagnosis::new(
+                "sudo is installed",
+                found.then_some(()).ok_or(anyhow!(
+                    "sudo is not installed, but managers, hooks or files use it"
+                )),
+            ));
+        }
+        diagnoses
+    }
+
+    /// Whether any manager, hook or file runs as root
+    fn uses_sudo(&self) -> bool {
+        let mut hooks = self.hooks.once.values().chain(self.hooks.update.values());
+        let written = |command: &str| command.starts_with("sudo ");
+        self.managers.values().any(|manager| {
+            let commands = [&manager.remove, &manager.update, &manager.refresh];
+            manager.sudo
+                || commands
+                    .into_iter()
+                    .flatten()
+                    .map(AsRef::as_ref)
+                    .chain(manager.add.iter().flat_map(Commands::steps))
+                    .any(written)
+        }) || hooks.any(|hook| hook.sudo || written(&hook.command))
+            || self.files.values().any(|file| file.enabled && file.sudo)
+    }
+
+    /// Maps each manager or hook to the ones that have to run after it
+    fn successors(&self) -> HashMap<&str, Vec<&str>> {
+        let managers = self
+            .managers
+            .iter()
+            .map(|(name, manager)| (name, &manager.before, &manager.after));
+        let hooks = self
+            .hooks
+            .once
+            .iter()
+            .chain(self.hooks.update.iter())
+            .map(|(name, hook)| (name, &hook.before, &hook.after));
+
+        let mut successors: HashMap<&str, Vec<&str>> = HashMap::new();
+        for (name, before, after) in managers.chain(hooks) {
+            for other in before {
+                successors.entry(name).or_default().push(other);
+            }
+            for other in after {
+                successors.entry(other).or_default().push(name);
+            }
+        }
+        successors
+    }
+
+    /// Whether `later` has to wait for `earlier` to finish because of `before`/`after`, or
+    /// because `earlier` is the refresh of the manager `later` changes packages of
+    pub fn depends_on(&self, later: &Change, earlier: &Change) -> bool {
+        if let Change::RawCommand { origin, .. } = earlier {
+            if later.manager() == Some(origin) {
+                return true;
+            }
+        }
+        // Scripts run one at a time, in file name order
+        if let (Some(earlier), Some(later)) = (script_name(earlier), script_name(later)) {
+            return earlier < later;
+        }
+        match (earlier.node(), later.node()) {
+            (Some(earlier), Some(later)) if earlier != later => {
+                runs_before(&self.successors(), earlier, later)
+            }
+            _ => false,
+        }
+    }
+
+    /// Orders the changes by their `before`/`after` relations, using the priority to order
+    /// changes that are not related
+    fn sort_changes(&self, changes: Vec<Change>) -> Result<Vec<Change>> {
+        let successors = self.successors();
+        let mut waiting_on = vec![0; changes.len()];
+        let mut unblocks = vec![Vec::new(); changes.len()];
+        for (i, earlier) in changes.iter().enumerate() {
+            for (j, later) in changes.iter().enumerate() {
+                if let (Some(a), Some(b)) = (earlier.node(), later.node()) {
+                    if a != b && runs_before(&successors, a, b) {
+                        waiting_on[j] += 1;
+                        unblocks[i].push(j);
+                    }
+                }
+            }
+        }
+
+        let priorities = changes
+            .iter()
+            .map(|change| change.priority(self))
+            .collect::<Vec<_>>();
+        let mut changes = changes.into_iter().map(Some).collect::<Vec<_>>();
+        let mut sorted = Vec::with_capacity(changes.len());
+        while sorted.len() < changes.len() {
+            // The index keeps the original order for ties, so removals stay before additions
+            let next = (0..changes.len())
+                .filter(|&i| changes[i].is_some() && waiting_on[i] == 0)
+                .min_by_key(|&i| (priorities[i], i))
+                .ok_or(anyhow!("Cycle in `before`/`after` relations"))?;
+            for &j in unblocks[next].iter() {
+                waiting_on[j] -= 1;
+            }
+            sorted.extend(changes[next].take());
+        }
+        Ok(sorted)
+    }
+
+    /// Loads the modules imported by the config file at `path`
+    pub fn load_dependencies(&mut self, path: &Path) -> Result<()> {
+        let root = path.canonicalize().unwrap_or_else(|_| path.into());
+        let mut conflicts = Vec::new();
+        let mut loaded = HashSet::new();
+        let policy = self.dotty.merge_policy();
+        self.load_imports(
+            &root,
+            &mut vec![root.clone()],
+            &mut loaded,
+            &mut conflicts,
+            policy,
+        )?;
+        self.remove = Removals::default();
+        self.modules = std::iter::once(root).chain(loaded).collect();
+        self.fill_defaults();
+
+        if (policy == MergePolicy::Error || self.dotty.strict_merge) && !conflicts.is_empty() {
+            return Err(anyhow!(
+                "Modules define the same entries differently:\n{}",
+                conflicts.join("\n")
+            ));
+        }
+        for conflict in conflicts {
+            eprintln!("{}", format!("Warning: {conflict}").yellow());
+        }
+        Ok(())
+    }
+
+    /// `stack` holds the chain of imports leading here to detect cycles, while `loaded` holds
+    /// every module imported so far so diamond shaped imports are only combined once
+    fn load_imports(
+        &mut self,
+        path: &Path,
+        stack: &mut Vec<PathBuf>,
+        loaded: &mut HashSet<PathBuf>,
+        conflicts: &mut Vec<String>,
+        policy: MergePolicy,
+    ) -> Result<()> {
+        if self.module.disable {
+            *self = Self::default();
+            return Ok(());
+        }
+
+        let entries = self
+            .managers
+            .keys()
+            .map(|name| format!("manager {name}"))
+            .chain(self.hooks.once.keys().map(|name| format!("hook {name}")))
+            .chain(self.hooks.update.keys().map(|name| format!("hook {name}")))
+            .chain(self.files.keys().map(|name| format!("file {name}")))
+            .collect::<Vec<_>>();
+        for entry in entries {
+            self.origins.insert(entry.into(), path.into());
+        }
+
+        let directory = path.parent().unwrap_or(Path::new("."));
+        self.template.load_files(directory)?;
+        let profile_files = self
+            .profiles
+            .values_mut()
+            .flat_map(|profile| profile.files.values_mut());
+        for file in self.files.values_mut().chain(profile_files) {
+            file.anchor(directory);
+        }
+        if let Some(script_dir) = &mut self.dotty.script_dir {
+            anchor_path(script_dir, directory);
+        }
+
+        let mut modules = self.module.import.clone();
+        let mut conditional = self.module.import_if.iter().collect::<Vec<_>>();
+        conditional.sort_by_key(|(module, _)| *module);
+        for (module, condition) in conditional {
+            let module = Import::Path(module.clone());
+            if condition.matches()? && !modules.contains(&module) {
+                modules.push(module);
+            }
+        }
+
+        for import in modules.into_iter() {
+            let module = import.spec();
+            let path = if module.starts_with(GIT_PREFIX) {
+                self.git_modules.push(module.clone());
+                git_module_path(&module, import.minisign_pubkey())?
+            } else {
+                directory.join(PathBuf::from_str(&module)?)
+            };
+            let path = path
+                .canonicalize()
+                .context(format!("Module {} not found", path.display()))?;
+
+            if let Some(start) = stack.iter().position(|parent| *parent == path) {
+                let cycle = stack[start..]
+                    .iter()
+                    .chain([&path])
+                    .map(|module| module.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+                return Err(DottyError::CyclicImport {
+                    cycle: cycle.into(),
+                }
+                .into());
+            }
+            if !loaded.insert(path.clone()) {
+                continue;
+            }
+
+            let content = std::fs::read_to_string(&path)?;
+            let mut config: Self = toml::from_str(&content)?;
+            stack.push(path.clone());
+            config.load_imports(&path, stack, loaded, conflicts, policy)?;
+            stack.pop();
+            conflicts.extend(self.conflicts(&config, policy));
+            conflicts.extend(self.template_conflicts(&config, &path, policy));
+            self.combine(config, policy)?;
+        }
+
+        // A module can also remove entries from the modules it imports itself
+        let removals = std::mem::take(&mut self.remove);
+        self.apply_removals(&removals);
+        self.remove = removals;
+
+        self.module = Module::default();
+        Ok(())
+    }
+
+    /// Every `git+` module the config imports, directly or through other modules
+    pub fn git_modules(&self) -> &[Box<str>] {
+        &self.git_modules
+    }
+
+    /// Describes every entry `other` would silently override when combined into this config
+    fn conflicts(&self, other: &Config, policy: MergePolicy) -> Vec<String> {
+        fn differing<'a, T: PartialEq>(
+            kind: &'a str,
+            ours: &'a HashMap<Box<str>, T>,
+            theirs: &'a HashMap<Box<str>, T>,
+        ) -> impl Iterator<Item = String> + 'a {
+            theirs.iter().filter_map(move |(name, value)| {
+                ours.get(name)
+                    .filter(|current| *current != value)
+                    .map(|_| format!("{kind} {name}"))
+            })
+        }
+
+        let mut entries = differing("manager", &self.managers, &other.managers)
+            .chain(differing("hook", &self.hooks.once, &other.hooks.once))
+            .chain(differing("hook", &self.hooks.update, &other.hooks.update))
+            .chain(differing("file", &self.files, &other.files))
+            .collect::<Vec<_>>();
+        entries.sort();
+
+        let origin = |config: &Config, entry: &str| {
+            config
+                .origins
+                .get(entry)
+                .map(|path| path.display().to_string())
+                .unwrap_or_else(|| "<unknown>".into())
+        };
+        let (winner, loser, verb) = match policy {
+            MergePolicy::FirstWins => (self, other, "is kept over"),
+            MergePolicy::Override | MergePolicy::Error => (other, self, "overrides"),
+        };
+        entries
+            .into_iter()
+            .map(|entry| {
+                format!(
+                    "{entry} from {} {verb} the one from {}",
+                    origin(winner, &entry),
+                    origin(loser, &entry)
+                )
+            })
+            .collect()
+    }
+
+    /// The template values of the module at `path` that an earlier module already set differently
+    fn template_conflicts(&self, other: &Config, path: &Path, policy: MergePolicy) -> Vec<String> {
+        let mut keys = Vec::new();
+        for (name, value) in other.template.0.iter() {
+            if let Some(current) = self.template.0.get(name) {
+                current.conflicts(value, &format!("template.{name}"), &mut keys);
+            }
+        }
+        keys.sort();
+
+        let outcome = match policy {
+            MergePolicy::FirstWins => "is ignored, the one set earlier is kept",
+            MergePolicy::Override | MergePolicy::Error => "overrides the one set earlier",
+        };
+        keys.into_iter()
+            .map(|key| format!("{key} from {} {outcome}", path.display()))
+            .collect()
+    }
+
+    /// Builds a change uninstalling the packages directly, rather than through `diff`
+    pub fn remove_change(&self, manager: &str, packages: Vec<Package>) -> Result<Change> {
+        let found = self
+            .managers
+            .get(manager)
+            .ok_or(DottyError::ManagerNotFound {
+                manager: manager.into(),
+            })?;
+        if found.remove.is_none() {
+            return Err(anyhow!("Manager {} has no `remove` command", manager));
+        }
+
+        Ok(Change::RemovePackage {
+            manager: manager.into(),
+            packages,
+        })
+    }
+
+    pub fn has_package(&self, manager: &str, name: &str) -> bool {
+        self.packages
+            .get(manager)
+            .is_some_and(|packages| packages.iter().any(|package| *package.name == *name))
+    }
+
+    /// Names of the packages the manager reports as installed, `None` if it has no `list` command
+    pub fn installed_packages(&self, manager: &str) -> Result<Option<HashSet<Box<str>>>> {
+        let found = self
+            .managers
+            .get(manager)
+            .ok_or(DottyError::ManagerNotFound {
+                manager: manager.into(),
+            })?;
+        let Some(command) = &found.list else {
+            return Ok(None);
+        };
+
+        let command = expand_command(command, self.dotty.strict_env)?;
+        let output = self.dotty.shell.command(&command)?.output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Listing the packages of {manager} with `{command}` failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let installed = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .map(Box::from)
+            .collect();
+        Ok(Some(installed))
+    }
+
+    /// The packages of each manager matching `query`, comparing this config with the state `old`
+    /// the same way `diff` does. Managers without any are left out
+    pub fn query_packages(
+        &self,
+        old: &Config,
+        query: PackageQuery,
+    ) -> BTreeMap<Box<str>, Vec<Package>> {
+        let (packages, others) = match query {
+            PackageQuery::List => (&self.packages, None),
+            PackageQuery::Added => (&self.packages, Some(&old.packages)),
+            PackageQuery::Orphaned => (&old.packages, Some(&self.packages)),
+        };
+
+        let empty = HashSet::new();
+        let mut result = BTreeMap::new();
+        for (manager, packages) in packages {
+            let others = others
+                .and_then(|others| others.get(manager))
+                .unwrap_or(&empty);
+            let mut matching = self
+                .package_difference(manager, packages, others)
+                .cloned()
+                .collect::<Vec<_>>();
+            if matching.is_empty() {
+                continue;
+            }
+            matching.sort();
+            result.insert(manager.clone(), matching);
+        }
+        result
+    }
+
+    /// Makes this state match what the managers of `config` report as installed. Packages that
+    /// are gone are forgotten, and packages from the config that are already installed are
+    /// recorded. Returns what changed for each manager.
+    pub fn reconcile(&mut self, config: &Config) -> Result<Vec<Reconciled>> {
+        let mut managers = config.managers.keys().collect::<Vec<_>>();
+        managers.sort();
+
+        let mut report = Vec::new();
+        for manager in managers {
+            let Some(installed) = config.installed_packages(manager)? else {
+                continue;
+            };
+            let current = self.packages.entry(manager.clone()).or_default();
+
+            let mut removed = current
+                .iter()
+                .filter(|package| !installed.contains(&package.name))
+                .map(|package| (**package).clone())
+                .collect::<Vec<_>>();
+            current.retain(|package| installed.contains(&package.name));
+
+            let mut added = Vec::new();
+            for package in config.packages.get(manager).into_iter().flatten() {
+                let known = current.iter().any(|x| x.name == package.name);
+                if installed.contains(&package.name) && !known {
+                    current.insert(package.clone());
+                    added.push((**package).clone());
+                }
+            }
+
+            added.sort();
+            removed.sort();
+            if !added.is_empty() || !removed.is_empty() {
+                report.push(Reconciled {
+                    manager: manager.clone(),
+                    added,
+                    removed,
+                });
+            }
+        }
+        Ok(report)
+    }
+
+    /// Forgets the packages by name, regardless of which version was installed
+    pub fn forget_packages(&mut self, manager: &str, packages: &[Package]) {
+        if let Some(current) = self.packages.get_mut(manager) {
+            current.retain(|package| !packages.iter().any(|x| x.name == package.name));
+        }
+    }
+
+    pub fn update(&self, state: &Config) -> Result<Vec<Change>> {
+        let (changes, _) = self.update_explained(state)?;
+        Ok(changes)
+    }
+
+    /// Like `update`, but also returns the update hooks skipped because they ran less than their
+    /// `min_interval` ago according to `state`
+    pub fn update_explained(&self, state: &Config) -> Result<(Vec<Change>, Vec<Skip>)> {
+        let mut changes = Vec::new();
+        let mut skips = Vec::new();
+        let empty = HashSet::new();
+        for (name, manager) in self.managers.iter() {
+            if let Some(command) = &manager.update {
+                let packages = self.packages.get(name).unwrap_or(&empty);
+
+                if !manager.separator().is_empty() {
+                    let joined = packages
                         .iter()
-                        .cloned()
+                        .map(|package| package.name.as_ref())
                         .collect::<Vec<_>>()
-                        .join(&manager.seperator);
+                        .join(manager.separator());
                     changes.push(Change::RawCommand {
                         command: command.replace("#:?", &joined).into(),
-                        priority: manager.priority,
+                        sudo: manager.sudo,
+                        priority: manager.priority(),
+                        origin: name.clone(),
+                        cwd: None,
+                        env: HashMap::new(),
+                        timeout: manager.timeout,
+                        retries: manager.retries,
+                        predicate: Predicate::default(),
                     });
                 } else {
                     for package in packages {
                         changes.push(Change::RawCommand {
-                            command: command.replace("#:?", package).into(),
-                            priority: manager.priority,
+                            command: package.substitute(command).into(),
+                            sudo: manager.sudo,
+                            priority: manager.priority(),
+                            origin: name.clone(),
+                            cwd: None,
+                            env: HashMap::new(),
+                            timeout: manager.timeout,
+                            retries: manager.retries,
+                            predicate: Predicate::default(),
                         });
                     }
                 }
             }
         }
 
-        for hook in self.hooks.update.values() {
-            changes.push(Change::RawCommand {
-                command: hook.command.clone(),
-                priority: hook.priority,
-            });
+        let now = unix_time(SystemTime::now());
+        for (name, hook) in self.hooks.update.iter().filter(|(_, hook)| hook.enabled) {
+            let last_run = state.hook_runs.get(name);
+            if let (Some(interval), Some(last_run)) = (hook.min_interval()?, last_run) {
+                if now.saturating_sub(*last_run) < interval.as_secs() {
+                    skips.push(Skip {
+                        entry: format!("hook {name}").into(),
+                        reason: SkipReason::RecentlyRun,
+                    });
+                    continue;
+                }
+            }
+            changes.push(Change::RawCommand {
+                command: hook.command.clone(),
+                sudo: hook.sudo,
+                priority: hook.priority(),
+                origin: name.clone(),
+                cwd: hook.cwd.clone(),
+                env: hook.env.clone(),
+                timeout: hook.timeout,
+                retries: Some(0),
+                predicate: hook.predicate(),
+            });
+        }
+
+        Ok((self.sort_changes(changes)?, skips))
+    }
+
+    /// Takes when throttled update hooks last ran from `old`, and records every one in `ran` as
+    /// having run now. Entries of hooks that are gone or no longer throttled are dropped
+    pub fn record_hook_runs(&mut self, old: &Config, ran: &[Change]) {
+        let throttled = |name: &str| {
+            self.hooks
+                .update
+                .get(name)
+                .is_some_and(|hook| hook.min_interval.is_some())
+        };
+        let mut runs = old
+            .hook_runs
+            .iter()
+            .filter(|(name, _)| throttled(name))
+            .map(|(name, time)| (name.clone(), *time))
+            .collect::<HashMap<_, _>>();
+        let now = unix_time(SystemTime::now());
+        for change in ran {
+            if let Change::RawCommand { origin, .. } = change {
+                if throttled(origin) {
+                    runs.insert(origin.clone(), now);
+                }
+            }
+        }
+        self.hook_runs = runs;
+    }
+
+    pub fn diff(&self, old: Config) -> Result<Vec<Change>> {
+        let (changes, _) = self.diff_explained(old, false, Level::Normal)?;
+        Ok(changes)
+    }
+
+    /// What dotty would write to `target`, as bytes since plain copies do not have to be text
+    pub fn desired_contents(&self, target: &Path) -> Result<Vec<u8>> {
+        let target = target.to_string_lossy();
+        let candidates = [resolve_path(&target), resolve_link_path(&target)];
+
+        let deployments = self.deployments()?;
+        let resolved = deployments
+            .iter()
+            .filter(|deployment| deployment.file.enabled)
+            .map(|deployment| deployment.resolve())
+            .collect::<Vec<_>>();
+        let overridden = find_overridden(&resolved)?;
+
+        let (deployment, source, _) = resolved
+            .into_iter()
+            .find(|(deployment, _, resolved)| {
+                !overridden.contains(deployment.name.as_ref()) && candidates.contains(resolved)
+            })
+            .ok_or(anyhow!("No enabled file deploys to {target}"))?;
+        let file = &deployment.file;
+
+        if !file.layers.is_empty() {
+            Ok(layers::merge(&file.layers)?.into_boxed_bytes().into_vec())
+        } else if !file.link && file.is_template(&source) {
+            Ok(self.render(&source)?.into_bytes())
+        } else if source.is_dir() {
+            Err(anyhow!(
+                "{} is a directory, only single files can be compared",
+                source.display()
+            ))
+        } else {
+            std::fs::read(&source).context(format!("Could not read {}", source.display()))
+        }
+    }
+
+    /// Like `diff`, but also returns every entry that was considered and why it was skipped.
+    /// With `force_files` every file is deployed again, even when it looks up to date.
+    pub fn diff_explained(
+        &self,
+        old: Config,
+        force_files: bool,
+        level: Level,
+    ) -> Result<(Vec<Change>, Vec<Skip>)> {
+        let mut changes = Vec::new();
+        let mut skips = Vec::new();
+
+        let managers = self.managers.keys().collect::<Vec<_>>();
+
+        // Since the hashmap returns references `unwrap_or` needs a reference for the default
+        let empty = HashSet::new();
+        for mananger in managers {
+            let new_packages = self.packages.get(mananger).unwrap_or(&empty);
+            let current_packages = old.packages.get(mananger).unwrap_or(&empty);
+
+            // Names are compared normalized, so the state never churns between spellings. A
+            // changed version shows up as both removed and added, so it is reinstalled
+            let added = self
+                .package_difference(mananger, new_packages, current_packages)
+                .cloned()
+                .collect::<Vec<_>>();
+            let removed = self
+                .package_difference(mananger, current_packages, new_packages)
+                .cloned()
+                .collect::<Vec<_>>();
+
+            for package in new_packages
+                .iter()
+                .filter(|package| !added.contains(package))
+            {
+                skips.push(Skip {
+                    entry: format!("{mananger}: {}", **package).into(),
+                    reason: SkipReason::PackagePresent,
+                });
+            }
+
+            // The refresh comes first so it is sorted before the removals too
+            let manager = &self.managers[mananger];
+            if let (Some(refresh), false) = (&manager.refresh, added.is_empty()) {
+                changes.push(Change::RawCommand {
+                    command: refresh.clone(),
+                    sudo: manager.sudo,
+                    priority: manager.priority(),
+                    origin: mananger.clone(),
+                    cwd: None,
+                    env: HashMap::new(),
+                    timeout: manager.timeout,
+                    retries: manager.retries,
+                    predicate: Predicate::default(),
+                });
+            }
+            if !removed.is_empty() {
+                changes.push(Change::RemovePackage {
+                    manager: mananger.clone(),
+                    packages: removed,
+                });
+            }
+            // Optional packages are added one at a time so a failing one does not take the
+            // others down with it
+            let (optional, required): (Vec<_>, Vec<_>) =
+                added.into_iter().partition(|package| package.optional);
+            if !required.is_empty() {
+                changes.push(Change::AddPackage {
+                    manager: mananger.clone(),
+                    packages: required,
+                });
+            }
+            for package in optional {
+                changes.push(Change::AddPackage {
+                    manager: mananger.clone(),
+                    packages: vec![package],
+                });
+            }
+        }
+
+        for (name, hook) in self.hooks.once.iter() {
+            if !hook.enabled {
+                skips.push(Skip {
+                    entry: format!("hook {name}").into(),
+                    reason: SkipReason::Disabled,
+                });
+                continue;
+            }
+
+            let run_hook = if let Some(old_value) = old.hooks.once.get(name) {
+                hook.command != old_value.command || old.failed_hooks.contains(name)
+            } else {
+                true
+            };
+            if run_hook {
+                changes.push(Change::RawCommand {
+                    command: hook.command.clone(),
+                    sudo: hook.sudo,
+                    priority: hook.priority(),
+                    origin: name.clone(),
+                    cwd: hook.cwd.clone(),
+                    env: hook.env.clone(),
+                    timeout: hook.timeout,
+                    retries: Some(0),
+                    predicate: hook.predicate(),
+                });
+            } else {
+                skips.push(Skip {
+                    entry: format!("hook {name}").into(),
+                    reason: SkipReason::HookUnchanged,
+                });
+            }
+        }
+
+        self.scripts(&mut changes, &mut skips)?;
+
+        let redo_all_templates = self.template != old.template || self.secrets != old.secrets;
+
+        let deployments = self.deployments()?;
+        let mut resolved = Vec::with_capacity(deployments.len());
+        for deployment in deployments.iter() {
+            if !deployment.file.enabled {
+                skips.push(Skip {
+                    entry: deployment.name.clone(),
+                    reason: SkipReason::Disabled,
+                });
+                continue;
+            }
+            resolved.push(deployment.resolve());
+        }
+        let overridden = find_overridden(&resolved)?;
+        let deployed = resolved
+            .iter()
+            .map(|(_, _, target)| target.clone())
+            .collect::<HashSet<_>>();
+
+        for (deployment, source, target) in resolved {
+            let name = deployment.name.as_ref();
+            let file = &deployment.file;
+            if overridden.contains(name) {
+                skips.push(Skip {
+                    entry: name.into(),
+                    reason: SkipReason::Overridden,
+                });
+                continue;
+            }
+            file.check_source(name, &source)?;
+            if force_files {
+                changes.push(Change::CopyFile(file.clone(), target));
+                continue;
+            }
+
+            level.log(
+                Level::Verbose,
+                format!("Resolved {} -> {}", source.display(), target.display()),
+            );
+
+            let mut notes = Vec::new();
+            let skip = Self::file_skip(
+                &old,
+                deployment,
+                &source,
+                &target,
+                redo_all_templates,
+                &mut notes,
+            )?;
+            for note in notes {
+                level.log(Level::Trace, format!("{}: {note}", target.display()));
+            }
+            match skip {
+                Some(reason) => skips.push(Skip {
+                    entry: target.to_string_lossy().into(),
+                    reason,
+                }),
+                None => changes.push(Change::CopyFile(file.clone(), target)),
+            }
+        }
+
+        for (file, target) in old.dropped_files(self) {
+            if deployed.contains(&target) || std::fs::symlink_metadata(&target).is_err() {
+                continue;
+            }
+            changes.push(Change::RemoveFile(file, target));
+        }
+
+        Ok((self.sort_changes(changes)?, skips))
+    }
+
+    /// Why an enabled file that is not overridden is skipped, `None` when it has to be copied.
+    /// Every check made on the way is described in `notes`, in the order they were made
+    fn file_skip(
+        old: &Config,
+        deployment: &Deployment,
+        source: &Path,
+        target: &Path,
+        redo_all_templates: bool,
+        notes: &mut Vec<String>,
+    ) -> Result<Option<SkipReason>> {
+        let file = &deployment.file;
+        if file.link {
+            let current = std::fs::read_link(target).ok();
+            notes.push(match &current {
+                Some(current) => format!("symlink points to {}", current.display()),
+                None => "no symlink in place".into(),
+            });
+            return Ok((current.as_deref() == Some(source)).then_some(SkipReason::LinkInPlace));
+        }
+
+        let old_file = old.files.get(deployment.key);
+        let is_new = old_file.is_none();
+        let exists = target.exists();
+        let is_template = file.is_template(source);
+        notes.push(
+            if is_new {
+                "new, not in the state"
+            } else {
+                "already in the state"
+            }
+            .into(),
+        );
+        notes.push(
+            if exists {
+                "target exists"
+            } else {
+                "target is missing"
+            }
+            .into(),
+        );
+        if source.is_dir() {
+            notes.push("source is a directory, which is always copied".into());
+        }
+        if is_template {
+            let context = if redo_all_templates {
+                "changed"
+            } else {
+                "unchanged"
+            };
+            notes.push(format!("template, template context or secrets {context}"));
+        }
+
+        // TODO: Make directory handling smarter
+        // TODO: Make template handling smarter
+        if is_new || !exists || source.is_dir() || (is_template && redo_all_templates) {
+            return Ok(None);
+        }
+        if file.metadata_changed(old_file.map(|old| &**old), target)? {
+            notes.push("mode or owner changed".into());
+            return Ok(None);
+        }
+        if let Some(stored) = old.hashes.get(deployment.name.as_ref()) {
+            let current = source_hash(file, source)?;
+            notes.push(format!(
+                "source hash {}, stored hash {stored}",
+                current.as_deref().unwrap_or("<none>")
+            ));
+            return Ok((current.as_ref() == Some(stored)).then_some(SkipReason::UpToDate));
+        }
+
+        let source_changed = if file.layers.is_empty() {
+            std::fs::metadata(source)?.modified()?
+        } else {
+            layers::latest_modified(&file.layers)?
+        };
+        let target_changed = std::fs::metadata(target)?.modified()?;
+        let time = |time: SystemTime| history::format_timestamp(unix_time(time));
+        notes.push(format!(
+            "no stored hash, source modified {}, target modified {}",
+            time(source_changed),
+            time(target_changed)
+        ));
+        Ok((source_changed <= target_changed).then_some(SkipReason::UpToDate))
+    }
+
+    /// Traces how `diff` decides whether to copy the file deploying to `target`: its paths, every
+    /// check made against `old` and the final decision, for each entry deploying there
+    pub fn explain_file(&self, old: &Config, target: &Path) -> Result<Vec<String>> {
+        let target = target.to_string_lossy();
+        let candidates = [resolve_path(&target), resolve_link_path(&target)];
+        let redo_all_templates = self.template != old.template || self.secrets != old.secrets;
+
+        let deployments = self.deployments()?;
+        let resolved = deployments
+            .iter()
+            .filter(|deployment| deployment.file.enabled)
+            .map(|deployment| deployment.resolve())
+            .collect::<Vec<_>>();
+        let overridden = find_overridden(&resolved)?;
+
+        let mut lines = Vec::new();
+        for deployment in deployments.iter() {
+            let (deployment, source, resolved) = deployment.resolve();
+            if !candidates.contains(&resolved) {
+                continue;
+            }
+            let name = deployment.name.as_ref();
+            let canonical = |path: &Path| match path.canonicalize() {
+                Ok(path) => path.display().to_string(),
+                Err(_) => {
+                    let path = std::path::absolute(path).unwrap_or_else(|_| path.into());
+                    format!("{} (missing)", path.display())
+                }
+            };
+            lines.push(format!("file {name}"));
+            lines.push(format!("  source: {}", canonical(&source)));
+            lines.push(format!("  target: {}", canonical(&resolved)));
+
+            let skip = if !deployment.file.enabled {
+                Some(SkipReason::Disabled)
+            } else if overridden.contains(name) {
+                Some(SkipReason::Overridden)
+            } else {
+                deployment.file.check_source(name, &source)?;
+                let mut notes = Vec::new();
+                let skip = Self::file_skip(
+                    old,
+                    deployment,
+                    &source,
+                    &resolved,
+                    redo_all_templates,
+                    &mut notes,
+                )?;
+                lines.extend(notes.into_iter().map(|note| format!("  {note}")));
+                skip
+            };
+            lines.push(match skip {
+                Some(reason) => format!("  decision: skip, {}", reason.describe()),
+                None => "  decision: copy".into(),
+            });
+        }
+        if lines.is_empty() {
+            return Err(anyhow!("No file deploys to {target}"));
+        }
+        Ok(lines)
+    }
+
+    /// Files deployed by this state whose entries are gone from `new`, with the source each came from
+    fn dropped_files(&self, new: &Config) -> Vec<(File, PathBuf)> {
+        let mut dropped = Vec::new();
+        for (key, file) in self.files.iter() {
+            if !file.enabled {
+                continue;
+            }
+            // Targets still listed by the entry are kept, even if it changed otherwise
+            let kept = new
+                .files
+                .get(key)
+                .map(|new| new.target_names(key).collect::<HashSet<_>>())
+                .unwrap_or_default();
+            for name in file.target_names(key) {
+                if kept.contains(name) {
+                    continue;
+                }
+                if !file.is_glob() {
+                    dropped.push(((**file).clone(), file.resolve_target(name)));
+                    continue;
+                }
+                dropped.extend(self.dropped_matches(file, name));
+            }
+        }
+        dropped.sort_by(|(_, a), (_, b)| a.cmp(b));
+        dropped
+    }
+
+    /// The files a glob entry deployed to the directory `name`, only known through the hashes
+    /// recorded for them
+    fn dropped_matches(&self, file: &File, name: &str) -> Vec<(File, PathBuf)> {
+        let mut dropped = Vec::new();
+        let directory = Path::new(file.source.as_ref())
+            .parent()
+            .unwrap_or(Path::new(""));
+        for hashed in self.hashes.keys() {
+            let Some(file_name) = hashed
+                .strip_prefix(name.trim_end_matches('/'))
+                .and_then(|rest| rest.strip_prefix('/'))
+                .filter(|rest| !rest.contains('/'))
+            else {
+                continue;
+            };
+            // The match may have been a template whose extension was stripped
+            let mut source = directory.join(file_name);
+            let template = directory.join(format!("{file_name}.tera"));
+            if self.dotty.strip_template_ext && !source.exists() && template.exists() {
+                source = template;
+            }
+            let source = source.to_string_lossy().into();
+            let file = File {
+                source,
+                ..file.clone()
+            };
+            dropped.push((file, resolve_path(hashed)));
+        }
+        dropped
+    }
+}
+
+/// Sets are serialized in no particular order, so every array is sorted to get a stable hash
+fn sort_arrays(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Array(items) => {
+            items.iter_mut().for_each(sort_arrays);
+            items.sort_by_cached_key(|item| item.to_string());
         }
+        serde_json::Value::Object(table) => table.values_mut().for_each(sort_arrays),
+        _ => {}
+    }
+}
 
-        changes.sort_by_key(|x| x.priority(self));
+/// Imports starting with this are fetched from a git repository instead of read from disk
+pub(crate) const GIT_PREFIX: &str = "git+";
 
-        Ok(changes)
+/// Fetches a `git+` import, checking its signature when `minisign_pubkey` is given
+#[cfg(feature = "git")]
+fn git_module_path(import: &str, minisign_pubkey: Option<&str>) -> Result<PathBuf> {
+    let module = crate::git::GitModule::parse(import)?;
+    if minisign_pubkey.is_some() {
+        crate::git::require_minisign()?;
     }
+    let path = module
+        .fetch()
+        .context(format!("Could not fetch module {import}"))?;
+    if let Some(pubkey) = minisign_pubkey {
+        module
+            .verify(pubkey)
+            .context(format!("Could not verify module {import}"))?;
+    }
+    Ok(path)
+}
 
-    pub fn diff(&self, old: Config) -> Result<Vec<Change>> {
-        let mut changes = Vec::new();
+#[cfg(not(feature = "git"))]
+fn git_module_path(import: &str, _minisign_pubkey: Option<&str>) -> Result<PathBuf> {
+    Err(anyhow!(
+        "Can not import {import}, dotty was built without the `git` feature"
+    ))
+}
 
-        let managers = self.managers.keys().collect::<Vec<_>>();
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
 
-        // Since the hashmap returns references `unwrap_or` needs a reference for the default
-        let empty = HashSet::new();
-        for mananger in managers {
-            let new_packages = self.packages.get(mananger).unwrap_or(&empty);
-            let current_packages = old.packages.get(mananger).unwrap_or(&empty);
+/// Parses intervals like `90s`, `30m`, `12h`, `7d` or `2w`, and sums of them like `1d12h`
+fn parse_interval(text: &str) -> Result<Duration> {
+    let invalid =
+        || anyhow!("Invalid interval `{text}`, expected something like `30m`, `12h` or `1w`");
+    let mut total = 0u64;
+    let mut rest = text.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let amount = rest[..digits].parse::<u64>().map_err(|_| invalid())?;
+        let unit = match rest[digits..].chars().next() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            Some('d') => 24 * 60 * 60,
+            Some('w') => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        total = amount
+            .checked_mul(unit)
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or_else(invalid)?;
+        rest = &rest[digits + 1..];
+    }
+    Ok(Duration::from_secs(total))
+}
 
-            let added = new_packages.difference(current_packages);
-            let removed = current_packages.difference(new_packages);
+fn sha256(content: &[u8]) -> Box<str> {
+    format!("{:x}", Sha256::digest(content)).into()
+}
 
-            let added = added.map(|x| (*x).clone()).collect::<Vec<_>>();
-            let removed = removed.map(|x| (*x).clone()).collect::<Vec<_>>();
+/// Hashes what a file deploys from, `None` for directories and links which are not compared
+fn source_hash(file: &File, source: &Path) -> Result<Option<Box<str>>> {
+    if file.link {
+        return Ok(None);
+    }
 
-            if !removed.is_empty() {
-                changes.push(Change::RemovePackage {
-                    manager: mananger.clone(),
-                    packages: removed,
-                });
-            }
-            if !added.is_empty() {
-                changes.push(Change::AddPackage {
-                    manager: mananger.clone(),
-                    packages: added,
-                });
-            }
+    let mut hasher = Sha256::new();
+    if file.layers.is_empty() {
+        if source.is_dir() {
+            return Ok(None);
+        }
+        hasher.update(std::fs::read(source).context(format!("reading {}", source.display()))?);
+    } else {
+        for layer in file.layers.iter() {
+            let layer = resolve_path(layer);
+            hasher.update(std::fs::read(&layer).context(format!("reading {}", layer.display()))?);
         }
+    }
+    Ok(Some(format!("{:x}", hasher.finalize()).into()))
+}
 
-        for (name, hook) in self.hooks.once.iter() {
-            let run_hook = if let Some(old_value) = old.hooks.once.get(name) {
-                hook.command != old_value.command
-            } else {
-                true
-            };
-            if run_hook {
-                changes.push(Change::RawCommand {
-                    command: hook.command.clone(),
-                    priority: hook.priority,
-                });
-            }
+/// Whether `to` can be reached from `from` by following the `before`/`after` relations
+fn runs_before(successors: &HashMap<&str, Vec<&str>>, from: &str, to: &str) -> bool {
+    let mut visited = HashSet::new();
+    let mut queue = successors.get(from).cloned().unwrap_or_default();
+    while let Some(current) = queue.pop() {
+        if current == to {
+            return true;
+        }
+        if visited.insert(current) {
+            queue.extend(successors.get(current).into_iter().flatten());
         }
+    }
+    false
+}
 
-        let redo_all_templates = self.template != old.template;
+/// Origins of changes running a file from `script_dir` start with this, followed by its name
+const SCRIPT_ORIGIN: &str = "script:";
 
-        for (target, file) in self.files.iter() {
-            let is_new = !old.files.contains_key(target);
+fn script_name(change: &Change) -> Option<&str> {
+    match change {
+        Change::RawCommand { origin, .. } => origin.strip_prefix(SCRIPT_ORIGIN),
+        _ => None,
+    }
+}
 
-            let source = shellexpand::tilde(&file.source);
-            let target = shellexpand::tilde(target);
+/// Quotes `text` as a single shell word
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
 
-            let source = PathBuf::from_str(&source).unwrap();
-            let target = PathBuf::from_str(&target).unwrap();
+/// Makes a relative path relative to `directory` instead of the working directory
+fn anchor_path(path: &mut Box<str>, directory: &Path) {
+    let relative = Path::new(path.as_ref());
+    if !path.starts_with('~') && relative.is_relative() {
+        let relative = relative.strip_prefix(".").unwrap_or(relative);
+        *path = directory.join(relative).to_string_lossy().into();
+    }
+}
 
-            let source = source.canonicalize().unwrap_or(source);
-            let target = target.canonicalize().unwrap_or(target);
+fn resolve_path(path: &str) -> PathBuf {
+    let path = shellexpand::tilde(path);
+    let path = PathBuf::from_str(&path).unwrap();
+    path.canonicalize().unwrap_or(path)
+}
 
-            let is_template = source.extension().is_some_and(|ext| ext == "tera");
+/// Like `resolve_path` but keeps the final component, so an existing symlink is not followed
+fn resolve_link_path(path: &str) -> PathBuf {
+    let path = shellexpand::tilde(path);
+    let path = PathBuf::from_str(&path).unwrap();
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => parent
+            .canonicalize()
+            .map(|parent| parent.join(name))
+            .unwrap_or(path),
+        _ => path,
+    }
+}
 
-            // TODO: Make directory handling smarter
-            // TODO: Make template handling smarter
-            if is_new || !target.exists() || source.is_dir() || (is_template && redo_all_templates)
-            {
-                changes.push(Change::CopyFile((**file).clone(), target));
-            } else {
-                let source_changed = std::fs::metadata(&source)?.modified()?;
-                let target_changed = std::fs::metadata(&target)?.modified()?;
+/// Finds files sharing a target with a higher priority file, which are skipped so the higher
+/// priority one wins. Files sharing both target and priority are an error.
+fn find_overridden<'a>(
+    resolved: &[(&'a Deployment<'_>, PathBuf, PathBuf)],
+) -> Result<HashSet<&'a str>> {
+    let mut by_target: HashMap<&PathBuf, Vec<(&str, &File)>> = HashMap::new();
+    for (deployment, _, target) in resolved {
+        by_target
+            .entry(target)
+            .or_default()
+            .push((&deployment.name, &deployment.file));
+    }
 
-                if source_changed > target_changed {
-                    changes.push(Change::CopyFile((**file).clone(), target));
-                }
+    let mut overridden = HashSet::new();
+    for (target, files) in by_target {
+        let Some(highest) = files.iter().map(|(_, file)| file.priority()).max() else {
+            continue;
+        };
+        let winners = files
+            .iter()
+            .filter(|(_, file)| file.priority() == highest)
+            .collect::<Vec<_>>();
+        if winners.len() > 1 {
+            let mut sources = winners
+                .iter()
+                .map(|(_, file)| file.source.clone())
+                .collect::<Vec<_>>();
+            sources.sort();
+            return Err(DottyError::DuplicateTarget {
+                target: target.clone(),
+                sources,
+                priority: highest,
             }
+            .into());
         }
 
-        changes.sort_by_key(|x| x.priority(self));
+        overridden.extend(
+            files
+                .iter()
+                .filter(|(_, file)| file.priority() != highest)
+                .map(|(name, _)| *name),
+        );
+    }
+    Ok(overridden)
+}
+
+#[derive(Debug)]
+pub enum SkipReason {
+    Disabled,
+    Overridden,
+    LinkInPlace,
+    PackagePresent,
+    HookUnchanged,
+    RecentlyRun,
+    UpToDate,
+    NotExecutable,
+}
 
-        Ok(changes)
+impl SkipReason {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Self::Disabled => "disabled",
+            Self::Overridden => "overridden by a higher priority file",
+            Self::LinkInPlace => "symlink already in place",
+            Self::PackagePresent => "package already present",
+            Self::HookUnchanged => "hook command unchanged",
+            Self::RecentlyRun => "ran less than `min_interval` ago",
+            Self::UpToDate => "source unchanged since last deploy",
+            Self::NotExecutable => "script not executable",
+        }
+    }
+}
+
+/// One check of `Config::diagnose`
+#[derive(Debug)]
+pub struct Diagnosis {
+    pub check: Box<str>,
+    pub result: Result<()>,
+}
+
+impl Diagnosis {
+    fn new(check: impl Into<Box<str>>, result: Result<()>) -> Self {
+        Self {
+            check: check.into(),
+            result,
+        }
     }
 }
 
+/// The program a command runs, skipping `sudo` and leading environment variable assignments.
+/// `None` when it starts with something only the shell can resolve, like a variable
+fn command_program(command: &str) -> Option<&str> {
+    let program = command
+        .split_whitespace()
+        .find(|word| *word != "sudo" && !word.contains('='))?;
+    let plain = !program.contains(['$', '`', '(', '{', '\'', '"']);
+    plain.then_some(program)
+}
+
+/// An entry that was considered by `Config::diff` but did not produce a change
 #[derive(Debug)]
+pub struct Skip {
+    pub entry: Box<str>,
+    pub reason: SkipReason,
+}
+
+impl Skip {
+    pub fn render(&self) -> colored::ColoredString {
+        format!("{} ({})", self.entry, self.reason.describe()).dimmed()
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Change {
     AddPackage {
         manager: Box<str>,
-        packages: Vec<Box<str>>,
+        packages: Vec<Package>,
     },
     RemovePackage {
         manager: Box<str>,
-        packages: Vec<Box<str>>,
+        packages: Vec<Package>,
     },
     CopyFile(File, PathBuf),
+    /// A file deployed by an entry that has since been dropped from the config
+    RemoveFile(File, PathBuf),
     RawCommand {
         command: Box<str>,
-        priority: u8,
+        sudo: bool,
+        priority: i32,
+        /// The manager or hook the command comes from
+        origin: Box<str>,
+        cwd: Option<Box<str>>,
+        env: HashMap<Box<str>, Box<str>>,
+        timeout: Option<u64>,
+        retries: Option<u32>,
+        predicate: Predicate,
     },
 }
 
 impl Change {
-    pub fn priority(&self, config: &Config) -> u8 {
+    pub fn priority(&self, config: &Config) -> i32 {
         match self {
             Self::AddPackage { manager, .. } | Self::RemovePackage { manager, .. } => {
                 let manager = config.managers.get(manager).unwrap();
-                manager.priority
+                manager.priority()
+            }
+            Self::RawCommand { priority, .. } => *priority,
+            Self::CopyFile(file, _) | Self::RemoveFile(file, _) => file.priority(),
+        }
+    }
+
+    pub fn is_destructive(&self) -> bool {
+        matches!(self, Self::RemovePackage { .. } | Self::RemoveFile(..))
+    }
+
+    /// The manager or hook this change belongs to, used for `before`/`after` ordering
+    pub fn node(&self) -> Option<&str> {
+        match self {
+            Self::AddPackage { manager, .. } | Self::RemovePackage { manager, .. } => Some(manager),
+            Self::RawCommand { origin, .. } => Some(origin),
+            Self::CopyFile(..) | Self::RemoveFile(..) => None,
+        }
+    }
+
+    /// The target a file change writes or removes, with whether it does so with sudo
+    pub fn file_target(&self) -> Option<(&Path, bool)> {
+        match self {
+            Self::CopyFile(file, target) | Self::RemoveFile(file, target) => {
+                Some((target, file.sudo))
+            }
+            Self::AddPackage { .. } | Self::RemovePackage { .. } | Self::RawCommand { .. } => None,
+        }
+    }
+
+    pub fn manager(&self) -> Option<&str> {
+        match self {
+            Self::AddPackage { manager, .. } | Self::RemovePackage { manager, .. } => Some(manager),
+            Self::CopyFile(..) | Self::RemoveFile(..) | Self::RawCommand { .. } => None,
+        }
+    }
+
+    /// Whether this change only adds optional packages, which do not stop the apply when they
+    /// fail
+    pub fn is_optional(&self) -> bool {
+        matches!(self, Self::AddPackage { packages, .. } if packages.iter().all(|package| package.optional))
+    }
+
+    /// A stable identity for this change, used to match it across runs when resuming
+    pub fn id(&self) -> Box<str> {
+        match self {
+            Self::AddPackage { manager, packages } => {
+                format!("add:{manager}:{}", join_sorted(packages, ",")).into()
+            }
+            Self::RemovePackage { manager, packages } => {
+                format!("remove:{manager}:{}", join_sorted(packages, ",")).into()
             }
-            Self::RawCommand { priority, .. } | Self::CopyFile(File { priority, .. }, _) => {
-                *priority
+            Self::CopyFile(_, target) => format!("copy:{}", target.display()).into(),
+            Self::RemoveFile(_, target) => format!("delete:{}", target.display()).into(),
+            // Keyed on the origin too, as different hooks can share the same command
+            Self::RawCommand {
+                command,
+                sudo,
+                origin,
+                ..
+            } => {
+                let sudo = if *sudo { "sudo " } else { "" };
+                format!("run:{origin}:{sudo}{command}").into()
             }
         }
     }
@@ -447,19 +3455,26 @@ impl Change {
             Self::AddPackage {
                 manager, packages, ..
             } => {
-                let joined = packages.join(", ");
+                let joined = join_sorted(packages, ", ");
                 format!("{}: {}", manager, joined).green()
             }
             Self::RemovePackage {
                 manager, packages, ..
             } => {
-                let joined = packages.join(", ");
+                let joined = join_sorted(packages, ", ");
                 format!("{}: {}", manager, joined).red()
             }
+            Self::CopyFile(file, target) if !file.layers.is_empty() => {
+                format!("{} -> {}", file.layers.join(" + "), target.display()).purple()
+            }
             Self::CopyFile(file, target) => {
                 format!("{} -> {}", file.source, target.display()).purple()
             }
-            Self::RawCommand { command, .. } => format!("{}", command).cyan(),
+            Self::RemoveFile(_, target) => format!("remove {}", target.display()).red(),
+            Self::RawCommand { command, sudo, .. } => {
+                let sudo = if *sudo { "sudo " } else { "" };
+                format!("{sudo}{command}").cyan()
+            }
         }
     }
 
@@ -469,57 +3484,199 @@ impl Change {
                 let manager = config
                     .managers
                     .get(&manager)
-                    .ok_or(anyhow::anyhow!("Manager {} not found", manager))?;
+                    .ok_or(DottyError::ManagerNotFound { manager })?;
 
-                if let Some(command) = &manager.add {
-                    construct_command(packages, manager, command)
-                } else {
-                    Ok(vec![])
+                let Some(add) = &manager.add else {
+                    return Ok(vec![]);
+                };
+                // Packages with their own arguments are installed on their own
+                let (custom, batched): (Vec<_>, Vec<_>) = packages
+                    .into_iter()
+                    .partition(|package| package.args.is_some());
+                let run = |command: String| Action::Run {
+                    command: command.into(),
+                    shell: config.dotty.shell.clone(),
+                    sudo: manager.sudo,
+                    cwd: None,
+                    env: HashMap::new(),
+                    timeout: manager.timeout,
+                    retries: manager.retries,
+                    predicate: Predicate::default(),
+                };
+
+                // Every step runs for all packages before the next one starts
+                let mut actions = Vec::new();
+                for step in add.steps() {
+                    let command = &expand_command(step, config.dotty.strict_env)?;
+                    if !command.contains("#:?") {
+                        actions.push(run(command.clone()));
+                        continue;
+                    }
+                    if !batched.is_empty() {
+                        actions.extend(construct_command(
+                            batched.clone(),
+                            manager,
+                            command,
+                            manager.retries,
+                            &config.dotty.shell,
+                        )?);
+                    }
+                    for package in &custom {
+                        let args = package.args.as_deref().unwrap_or_default();
+                        actions.push(run(format!("{} {args}", package.substitute(command))));
+                    }
                 }
+                Ok(actions)
             }
             Self::RemovePackage { manager, packages } => {
                 let manager = config
                     .managers
                     .get(&manager)
-                    .ok_or(anyhow::anyhow!("Manager {} not found", manager))?;
+                    .ok_or(DottyError::ManagerNotFound { manager })?;
 
+                // Removals are not retried, a failure there is rarely transient
                 if let Some(command) = &manager.remove {
-                    construct_command(packages, manager, command)
+                    let command = &expand_command(command, config.dotty.strict_env)?;
+                    construct_command(packages, manager, command, Some(0), &config.dotty.shell)
                 } else {
                     Ok(vec![])
                 }
             }
-            Self::RawCommand { command, .. } => Ok(vec![Action::Run {
+            Self::RawCommand {
                 command,
-                sudo: false,
+                sudo,
+                cwd,
+                env,
+                timeout,
+                retries,
+                predicate,
+                ..
+            } => Ok(vec![Action::Run {
+                command: expand_command(&command, config.dotty.strict_env)?.into(),
+                shell: config.dotty.shell.clone(),
+                sudo,
+                cwd: cwd.map(|cwd| resolve_path(&cwd)),
+                env,
+                timeout,
+                retries,
+                predicate,
             }]),
-            Self::CopyFile(file, target) => {
+            Self::RemoveFile(file, target) => {
                 let mut actions = Vec::with_capacity(2);
+                if let Some(backup) = config.dotty.backup(&target, file.sudo) {
+                    actions.push(backup);
+                }
+                actions.push(Action::Remove {
+                    source: resolve_path(&file.source),
+                    target,
+                    sudo: file.sudo,
+                });
+                Ok(actions)
+            }
+            Self::CopyFile(file, target) => {
+                let mut actions = Vec::with_capacity(3);
                 let source = PathBuf::from_str(&file.source).unwrap();
+                file.verify_source(&resolve_path(&file.source))?;
+
+                let is_template = file.is_template(&source);
+
+                let backup = config.dotty.backup(&target, file.sudo);
+                let restore = match &backup {
+                    Some(Action::Backup { backup, .. }) => Some(backup.clone()),
+                    _ => None,
+                };
+                actions.extend(backup);
+
+                // Hash of what the target should contain, for files that are written
+                let mut expected = None;
+                if file.link {
+                    if is_template || !file.layers.is_empty() {
+                        return Err(anyhow!("Can not use `link` with templates or layers"));
+                    }
+                    if file.mode.is_some() {
+                        return Err(anyhow!("Can not use `mode` with `link`"));
+                    }
+                    if file.owner.is_some() || file.group.is_some() {
+                        return Err(anyhow!("Can not use `owner` or `group` with `link`"));
+                    }
 
-                let is_template = source.extension().is_some_and(|ext| ext == "tera");
+                    let source = resolve_path(&file.source);
+                    if file.sudo {
+                        actions.push(Action::SymlinkSudo(source, target.clone()));
+                    } else {
+                        actions.push(Action::Symlink(source, target.clone()));
+                    }
+                } else if !file.layers.is_empty() {
+                    if file.sudo {
+                        return Err(anyhow!("Can not use `sudo` with layers"));
+                    }
 
-                if is_template {
+                    let merged = layers::merge(&file.layers)?;
+                    expected = Some(sha256(merged.as_bytes()));
+                    actions.push(Action::StoreFile(merged, target.clone()));
+                } else if is_template {
                     if file.sudo {
                         return Err(anyhow!("Can not use `sudo` with templates"));
                     }
 
-                    let mut templater = tera::Tera::default();
-                    templater.add_template_file(source, Some("template"))?;
-                    let context = tera::Context::from_serialize(&config.template)?;
-                    let rendered = templater.render("template", &context)?;
+                    let rendered = config.render(&source)?;
+                    expected = Some(sha256(rendered.as_bytes()));
 
-                    actions.push(Action::StoreFile(rendered.into_boxed_str(), target));
+                    actions.push(Action::StoreFile(rendered.into_boxed_str(), target.clone()));
                 } else if file.sudo {
-                    actions.push(Action::CopySudo(source, target));
+                    if !file.exclude.is_empty() && source.is_dir() {
+                        return Err(anyhow!("Can not use `exclude` with `sudo`"));
+                    }
+                    if file.mirror {
+                        return Err(anyhow!("Can not use `mirror` with `sudo`"));
+                    }
+                    expected = source_hash(&file, &source)?;
+                    actions.push(Action::CopySudo(source, target.clone()));
+                } else if source.is_dir() {
+                    actions.push(Action::CopyDir {
+                        source,
+                        target: target.clone(),
+                        exclude: [&config.dotty.exclude[..], &file.exclude[..]].concat(),
+                        mirror: file.mirror,
+                    });
                 } else {
-                    actions.push(Action::Copy(source, target));
+                    if file.mirror {
+                        return Err(anyhow!("`mirror` only applies to directories"));
+                    }
+                    expected = source_hash(&file, &source)?;
+                    actions.push(Action::Copy(source, target.clone()));
+                }
+
+                if let Some(hash) = expected.filter(|_| config.dotty.verify) {
+                    actions.push(Action::Verify {
+                        target: target.clone(),
+                        hash,
+                        restore,
+                        sudo: file.sudo,
+                    });
+                }
+
+                if let Some(mode) = file.mode()? {
+                    actions.push(Action::SetMode {
+                        target: target.clone(),
+                        mode,
+                        sudo: file.sudo,
+                    });
+                }
+                if let Some(owner) = file.ownership()? {
+                    actions.push(Action::SetOwner { target, owner });
                 }
 
                 if let Some(command) = &file.post_hook {
                     actions.push(Action::Run {
                         command: command.clone(),
+                        shell: config.dotty.shell.clone(),
                         sudo: false,
+                        cwd: None,
+                        env: HashMap::new(),
+                        timeout: None,
+                        retries: Some(0),
+                        predicate: Predicate::default(),
                     })
                 }
                 Ok(actions)
@@ -528,90 +3685,710 @@ impl Change {
     }
 }
 
+/// How changes are serialized, with named fields in place of the tuple variants
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum ChangeRepr<'a> {
+    AddPackage {
+        manager: &'a str,
+        packages: &'a [Package],
+    },
+    RemovePackage {
+        manager: &'a str,
+        packages: &'a [Package],
+    },
+    CopyFile {
+        source: &'a str,
+        layers: &'a [Box<str>],
+        target: &'a Path,
+    },
+    RemoveFile {
+        target: &'a Path,
+    },
+    Run {
+        command: &'a str,
+        sudo: bool,
+        origin: &'a str,
+    },
+}
+
+impl Serialize for Change {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        let repr = match self {
+            Self::AddPackage { manager, packages } => ChangeRepr::AddPackage { manager, packages },
+            Self::RemovePackage { manager, packages } => {
+                ChangeRepr::RemovePackage { manager, packages }
+            }
+            Self::CopyFile(file, target) => ChangeRepr::CopyFile {
+                source: &file.source,
+                layers: &file.layers,
+                target,
+            },
+            Self::RemoveFile(_, target) => ChangeRepr::RemoveFile { target },
+            Self::RawCommand {
+                command,
+                sudo,
+                origin,
+                ..
+            } => ChangeRepr::Run {
+                command,
+                sudo: *sudo,
+                origin,
+            },
+        };
+        repr.serialize(serializer)
+    }
+}
+
+fn render_template(
+    includes: &tera::Tera,
+    source: &Path,
+    context: tera::Context,
+    strict: bool,
+) -> Result<String> {
+    let mut templater = includes.clone();
+    templater
+        .add_template_file(source, Some("template"))
+        .map_err(|err| {
+            anyhow!(
+                "Invalid template {}: {}",
+                source.display(),
+                root_cause(&err)
+            )
+        })?;
+
+    let mut context = context;
+    let mut defaulted = HashSet::new();
+    loop {
+        let err = match templater.render("template", &context) {
+            Ok(rendered) => return Ok(rendered),
+            Err(err) => err,
+        };
+        let reason = root_cause(&err);
+        let missing = reason
+            .strip_prefix("Variable `")
+            .and_then(|rest| rest.split_once('`'))
+            .map(|(name, _)| name.to_owned())
+            .filter(|_| reason.contains("not found in context"));
+
+        let Some(missing) = missing else {
+            return Err(DottyError::TemplateRender {
+                template: source.to_owned(),
+                reason,
+            }
+            .into());
+        };
+        if strict || !defaulted.insert(missing.clone()) {
+            return Err(anyhow!(
+                "Template {} uses {missing}, which is not defined in [template]",
+                source.display()
+            ));
+        }
+
+        let mut value = context.into_json();
+        default_variable(&mut value, &missing);
+        context = tera::Context::from_value(value)?;
+    }
+}
+
+/// Sets the dotted `path` to an empty string, leaving it alone if a parent is not a table
+fn default_variable(value: &mut serde_json::Value, path: &str) {
+    let mut current = value;
+    let mut parts = path.split('.').peekable();
+    while let Some(part) = parts.next() {
+        let Some(table) = current.as_object_mut() else {
+            return;
+        };
+        let fallback = if parts.peek().is_some() {
+            serde_json::Value::Object(serde_json::Map::new())
+        } else {
+            serde_json::Value::String(String::new())
+        };
+        current = table.entry(part).or_insert(fallback);
+    }
+}
+
+fn root_cause(err: &(dyn std::error::Error + 'static)) -> String {
+    let mut current = err;
+    while let Some(source) = current.source() {
+        current = source;
+    }
+    current.to_string()
+}
+
+fn join_sorted(packages: &[Package], separator: &str) -> String {
+    let mut packages = packages.iter().map(Package::to_string).collect::<Vec<_>>();
+    packages.sort();
+    packages.join(separator)
+}
+
+/// Expands `~` and environment variables in a command, undefined variables are left for the shell
+/// unless `strict` is set
+fn expand_command(command: &str, strict: bool) -> Result<String> {
+    let expanded = shellexpand::env_with_context(command, |name| match std::env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(_) if !strict => Ok(None),
+        Err(err) => Err(err),
+    })
+    .map_err(|err| anyhow!("`{command}` uses ${}, which is not set", err.var_name))?;
+    Ok(shellexpand::tilde(&expanded).into_owned())
+}
+
 fn construct_command(
-    packages: Vec<Box<str>>,
+    packages: Vec<Package>,
     manager: &Manager,
     command: &str,
+    retries: Option<u32>,
+    shell: &Shell,
 ) -> std::result::Result<Vec<Action>, anyhow::Error> {
-    if !manager.seperator.is_empty() {
-        let args = packages.join(&manager.seperator);
+    // Versions are per package, so commands using them can not be batched
+    if !manager.separator().is_empty() && !command.contains("#:v?") {
+        let args = packages
+            .iter()
+            .map(|package| package.name.as_ref())
+            .collect::<Vec<_>>()
+            .join(manager.separator());
         Ok(vec![Action::Run {
             command: command.replace("#:?", &args).into(),
+            shell: shell.clone(),
             sudo: manager.sudo,
+            cwd: None,
+            env: HashMap::new(),
+            timeout: manager.timeout,
+            retries,
+            predicate: Predicate::default(),
         }])
     } else {
         Ok(packages
             .into_iter()
             .map(|x| Action::Run {
-                command: command.replace("#:?", &x).into(),
+                command: x.substitute(command).into(),
+                shell: shell.clone(),
                 sudo: manager.sudo,
+                cwd: None,
+                env: HashMap::new(),
+                timeout: manager.timeout,
+                retries,
+                predicate: Predicate::default(),
             })
             .collect())
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Action {
-    Run { command: Box<str>, sudo: bool },
+    Run {
+        command: Box<str>,
+        shell: Shell,
+        sudo: bool,
+        cwd: Option<PathBuf>,
+        env: HashMap<Box<str>, Box<str>>,
+        /// Seconds before the command is killed
+        timeout: Option<u64>,
+        /// Times the command is retried when it fails, `None` until filled in from `--retries`
+        retries: Option<u32>,
+        /// Decides whether the command runs at all
+        predicate: Predicate,
+    },
     Copy(PathBuf, PathBuf),
+    /// Copies the files of a directory that changed, leaving out what matches `exclude` and
+    /// deleting what is only in the target when `mirror` is set
+    CopyDir {
+        source: PathBuf,
+        target: PathBuf,
+        exclude: Vec<Box<str>>,
+        mirror: bool,
+    },
     CopySudo(PathBuf, PathBuf),
+    Symlink(PathBuf, PathBuf),
+    SymlinkSudo(PathBuf, PathBuf),
     StoreFile(Box<str>, PathBuf),
+    SetMode {
+        target: PathBuf,
+        mode: u32,
+        sudo: bool,
+    },
+    Backup {
+        target: PathBuf,
+        backup: PathBuf,
+        sudo: bool,
+    },
+    /// Changes the owner of the target with `sudo chown`, `owner` being `user:group`, `user` or
+    /// `:group`
+    SetOwner {
+        target: PathBuf,
+        owner: Box<str>,
+    },
+    /// Errors when the target's contents do not hash to `hash`, copying `restore` back first
+    Verify {
+        target: PathBuf,
+        hash: Box<str>,
+        restore: Option<PathBuf>,
+        sudo: bool,
+    },
+    /// Deletes a deployed file, for directories only the files that exist in `source`
+    Remove {
+        source: PathBuf,
+        target: PathBuf,
+        sudo: bool,
+    },
+}
+
+/// How actions are serialized, with named fields in place of the tuple variants
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum ActionRepr<'a> {
+    Run {
+        command: &'a str,
+        sudo: bool,
+        cwd: Option<&'a Path>,
+        when: Option<&'a str>,
+        unless: Option<&'a str>,
+    },
+    Copy {
+        source: &'a Path,
+        target: &'a Path,
+        sudo: bool,
+    },
+    CopyDir {
+        source: &'a Path,
+        target: &'a Path,
+        exclude: &'a [Box<str>],
+        mirror: bool,
+    },
+    Symlink {
+        source: &'a Path,
+        target: &'a Path,
+        sudo: bool,
+    },
+    StoreFile {
+        target: &'a Path,
+    },
+    SetMode {
+        target: &'a Path,
+        mode: String,
+        sudo: bool,
+    },
+    Backup {
+        target: &'a Path,
+        backup: &'a Path,
+        sudo: bool,
+    },
+    SetOwner {
+        target: &'a Path,
+        owner: &'a str,
+    },
+    Verify {
+        target: &'a Path,
+        hash: &'a str,
+    },
+    Remove {
+        target: &'a Path,
+        sudo: bool,
+    },
+}
+
+impl Serialize for Action {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        let repr = match self {
+            Self::Run {
+                command,
+                sudo,
+                cwd,
+                predicate,
+                ..
+            } => ActionRepr::Run {
+                command,
+                sudo: *sudo,
+                cwd: cwd.as_deref(),
+                when: predicate.when.as_deref(),
+                unless: predicate.unless.as_deref(),
+            },
+            Self::Copy(source, target) | Self::CopySudo(source, target) => ActionRepr::Copy {
+                source,
+                target,
+                sudo: matches!(self, Self::CopySudo(..)),
+            },
+            Self::CopyDir {
+                source,
+                target,
+                exclude,
+                mirror,
+            } => ActionRepr::CopyDir {
+                source,
+                target,
+                exclude,
+                mirror: *mirror,
+            },
+            Self::Symlink(source, target) | Self::SymlinkSudo(source, target) => {
+                ActionRepr::Symlink {
+                    source,
+                    target,
+                    sudo: matches!(self, Self::SymlinkSudo(..)),
+                }
+            }
+            Self::StoreFile(_, target) => ActionRepr::StoreFile { target },
+            Self::SetMode { target, mode, sudo } => ActionRepr::SetMode {
+                target,
+                mode: format!("{mode:04o}"),
+                sudo: *sudo,
+            },
+            Self::Backup {
+                target,
+                backup,
+                sudo,
+            } => ActionRepr::Backup {
+                target,
+                backup,
+                sudo: *sudo,
+            },
+            Self::SetOwner { target, owner } => ActionRepr::SetOwner { target, owner },
+            Self::Verify { target, hash, .. } => ActionRepr::Verify { target, hash },
+            Self::Remove { target, sudo, .. } => ActionRepr::Remove {
+                target,
+                sudo: *sudo,
+            },
+        };
+        repr.serialize(serializer)
+    }
 }
 
 impl Action {
+    pub fn is_destructive(&self) -> bool {
+        matches!(
+            self,
+            Self::CopySudo(..)
+                | Self::SymlinkSudo(..)
+                | Self::SetMode { sudo: true, .. }
+                | Self::SetOwner { .. }
+                | Self::Run { sudo: true, .. }
+                | Self::Remove { sudo: true, .. }
+        )
+    }
+
+    /// Uses `retries` for commands that do not choose their own
+    pub fn default_retries(&mut self, default: u32) {
+        if let Self::Run { retries, .. } = self {
+            retries.get_or_insert(default);
+        }
+    }
+
+    /// Whether running the action invokes sudo, including commands written with it
+    pub fn uses_sudo(&self) -> bool {
+        match self {
+            Self::Run { command, sudo, .. } => *sudo || command.starts_with("sudo "),
+            Self::CopySudo(..) | Self::SymlinkSudo(..) | Self::SetOwner { .. } => true,
+            Self::SetMode { sudo, .. }
+            | Self::Backup { sudo, .. }
+            | Self::Verify { sudo, .. }
+            | Self::Remove { sudo, .. } => *sudo,
+            Self::Copy(..) | Self::CopyDir { .. } | Self::Symlink(..) | Self::StoreFile(..) => {
+                false
+            }
+        }
+    }
+
     pub fn render(&self) -> colored::ColoredString {
         match self {
             Self::Run {
                 command,
-                sudo: false,
-            } => format!("{}", command).yellow(),
-            Self::Run {
-                command,
-                sudo: true,
-            } => format!("sudo {}", command).yellow(),
+                sudo,
+                cwd,
+                predicate,
+                ..
+            } => {
+                let sudo = if *sudo { "sudo " } else { "" };
+                let predicate = predicate.suffix();
+                match cwd {
+                    Some(cwd) => {
+                        format!("{sudo}{command} (in {}){predicate}", cwd.display()).yellow()
+                    }
+                    None => format!("{sudo}{command}{predicate}").yellow(),
+                }
+            }
             Self::Copy(source, target) | Self::CopySudo(source, target) => {
                 format!("{} -> {}", source.display(), target.display()).purple()
             }
+            Self::CopyDir {
+                source,
+                target,
+                exclude,
+                mirror,
+            } => {
+                let mut description = format!("{} -> {}", source.display(), target.display());
+                if !exclude.is_empty() {
+                    description.push_str(&format!(" (excluding {})", exclude.join(", ")));
+                }
+                if *mirror {
+                    description.push_str(" (mirrored)");
+                }
+                description.purple()
+            }
+            Self::Symlink(source, target) | Self::SymlinkSudo(source, target) => {
+                format!("{} <- {}", source.display(), target.display()).purple()
+            }
             Self::StoreFile(_, target) => format!("<template> -> {}", target.display()).purple(),
+            Self::SetMode { target, mode, .. } => {
+                format!("chmod {mode:04o} {}", target.display()).purple()
+            }
+            Self::Backup { target, backup, .. } => {
+                format!("backup {} -> {}", target.display(), backup.display()).blue()
+            }
+            Self::SetOwner { target, owner } => {
+                format!("chown {owner} {}", target.display()).purple()
+            }
+            Self::Verify { target, .. } => format!("verify {}", target.display()).blue(),
+            Self::Remove { target, .. } => format!("rm {}", target.display()).red(),
         }
     }
 
-    pub fn execute(self) -> Result<()> {
+    pub fn execute(&self, level: Level) -> Result<()> {
         match self {
-            Self::Run { command, sudo } => {
-                let command = if sudo {
+            Self::Run {
+                command,
+                shell,
+                sudo,
+                cwd,
+                env,
+                timeout,
+                retries,
+                predicate,
+            } => {
+                if let Some(reason) = predicate.skip_reason(shell, cwd.as_deref(), env)? {
+                    level.log(Level::Normal, format!("[-] Skipped {command}, {reason}"));
+                    return Ok(());
+                }
+                let command = if *sudo {
                     format!("sudo {}", command)
                 } else {
-                    command.into()
+                    command.to_string()
                 };
 
-                std::process::Command::new("sh")
-                    .arg("-c")
-                    .arg(&command)
-                    .status()?
-                    .exit_ok()?;
+                let mut process = shell.command(&command)?;
+                level.log(Level::Verbose, format!("{process:?}"));
+                process.envs(env.iter().map(|(key, value)| (&**key, &**value)));
+                if let Some(cwd) = cwd {
+                    process.current_dir(cwd);
+                }
+                if level == Level::Quiet {
+                    process.stdout(Stdio::null());
+                }
+                let retries = retries.unwrap_or_default();
+                let mut attempt = 0;
+                loop {
+                    let result = match timeout {
+                        Some(timeout) => run_with_timeout(&mut process, &command, *timeout),
+                        None => process
+                            .status()
+                            .map_err(anyhow::Error::from)
+                            .and_then(|status| Ok(DottyError::check_status(&command, status)?)),
+                    };
+                    let Err(err) = result else {
+                        break;
+                    };
+                    if attempt >= retries {
+                        return Err(err);
+                    }
+
+                    attempt += 1;
+                    let delay = Duration::from_secs(1 << (attempt - 1).min(6));
+                    level.log(
+                        Level::Normal,
+                        format!(
+                            "[~] {err}, retrying in {}s ({attempt}/{retries})",
+                            delay.as_secs()
+                        )
+                        .yellow(),
+                    );
+                    std::thread::sleep(delay);
+                }
             }
             Self::Copy(source, target) => {
-                if source.is_dir() {
-                    std::fs::create_dir_all(&target)?;
-                    fs_extra::dir::copy(
-                        &source,
-                        &target,
-                        &CopyOptions::new().overwrite(true).content_only(true),
-                    )?;
-                } else {
-                    let parent = target.parent().unwrap();
-                    std::fs::create_dir_all(parent)?;
-                    std::fs::copy(&source, &target)?;
-                }
+                level.log(
+                    Level::Verbose,
+                    format!("Copying {} to {}", source.display(), target.display()),
+                );
+                let parent = target.parent().unwrap();
+                std::fs::create_dir_all(parent)
+                    .map_err(|err| permission_denied(err, parent, "creating"))?;
+                std::fs::copy(source, target)
+                    .map_err(|err| permission_denied(err, target, "writing"))?;
+            }
+            Self::CopyDir {
+                source,
+                target,
+                exclude,
+                mirror,
+            } => {
+                level.log(
+                    Level::Verbose,
+                    format!("Syncing {} to {}", source.display(), target.display()),
+                );
+                sync_dir(
+                    source,
+                    target,
+                    Path::new(""),
+                    &exclusions(exclude)?,
+                    *mirror,
+                    level,
+                )?;
             }
             Self::CopySudo(source, target) => {
-                sudo_copy(&source, &target)?;
+                level.log(
+                    Level::Verbose,
+                    format!(
+                        "Copying {} to {} as root",
+                        source.display(),
+                        target.display()
+                    ),
+                );
+                sudo_copy(source, target)?;
             }
             Self::StoreFile(content, target) => {
+                level.log(Level::Verbose, format!("Writing {}", target.display()));
+                let parent = target.parent().unwrap();
+                std::fs::create_dir_all(parent)
+                    .map_err(|err| permission_denied(err, parent, "creating"))?;
+                std::fs::write(target, content.as_ref())
+                    .map_err(|err| permission_denied(err, target, "writing"))?;
+            }
+            Self::SetMode { target, mode, sudo } => {
+                level.log(
+                    Level::Verbose,
+                    format!("Setting mode of {} to {mode:04o}", target.display()),
+                );
+                if *sudo {
+                    sudo_chmod(target, *mode)?;
+                } else {
+                    std::fs::set_permissions(target, std::fs::Permissions::from_mode(*mode))
+                        .map_err(|err| permission_denied(err, target, "changing the mode of"))?;
+                }
+            }
+            Self::Symlink(source, target) => {
+                level.log(
+                    Level::Verbose,
+                    format!("Linking {} to {}", target.display(), source.display()),
+                );
                 let parent = target.parent().unwrap();
-                std::fs::create_dir_all(parent)?;
-                std::fs::write(target, content.as_ref())?;
+                std::fs::create_dir_all(parent)
+                    .map_err(|err| permission_denied(err, parent, "creating"))?;
+                if let Ok(metadata) = std::fs::symlink_metadata(target) {
+                    if metadata.is_dir() {
+                        return Err(anyhow!(
+                            "Refusing to replace directory {} with a symlink",
+                            target.display()
+                        ));
+                    }
+                    std::fs::remove_file(target)
+                        .map_err(|err| permission_denied(err, target, "removing"))?;
+                }
+                std::os::unix::fs::symlink(source, target)
+                    .map_err(|err| permission_denied(err, target, "writing"))?;
+            }
+            Self::Backup {
+                target,
+                backup,
+                sudo,
+            } => {
+                // The target might have been removed since the action was created
+                if std::fs::symlink_metadata(target).is_err() {
+                    return Ok(());
+                }
+                level.log(
+                    Level::Verbose,
+                    format!("Backing up {} to {}", target.display(), backup.display()),
+                );
+
+                if *sudo {
+                    sudo_backup(target, backup)?;
+                } else if target.is_dir() {
+                    std::fs::create_dir_all(backup)?;
+                    fs_extra::dir::copy(target, backup, &CopyOptions::new().content_only(true))?;
+                } else {
+                    std::fs::create_dir_all(backup.parent().unwrap())?;
+                    std::fs::copy(target, backup)?;
+                }
+            }
+            Self::SetOwner { target, owner } => {
+                level.log(
+                    Level::Verbose,
+                    format!("Setting owner of {} to {owner}", target.display()),
+                );
+                sudo_chown(target, owner)?;
+            }
+            Self::Verify {
+                target,
+                hash,
+                restore,
+                sudo,
+            } => {
+                level.log(Level::Verbose, format!("Verifying {}", target.display()));
+                let content = if *sudo {
+                    sudo_read(target)
+                } else {
+                    std::fs::read(target)
+                }
+                .map_err(|err| permission_denied(err, target, "reading"))?;
+                if sha256(&content) == *hash {
+                    return Ok(());
+                }
+
+                let Some(restore) = restore else {
+                    return Err(anyhow!(
+                        "{} does not match what was written to it",
+                        target.display()
+                    ));
+                };
+                if *sudo {
+                    sudo_copy_file(restore, target)?;
+                } else {
+                    std::fs::copy(restore, target)
+                        .map_err(|err| permission_denied(err, target, "writing"))?;
+                }
+                return Err(anyhow!(
+                    "{} does not match what was written to it, restored the backup at {}",
+                    target.display(),
+                    restore.display()
+                ));
+            }
+            Self::SymlinkSudo(source, target) => {
+                level.log(
+                    Level::Verbose,
+                    format!(
+                        "Linking {} to {} as root",
+                        target.display(),
+                        source.display()
+                    ),
+                );
+                sudo_symlink(source, target)?;
+            }
+            Self::Remove {
+                source,
+                target,
+                sudo,
+            } => {
+                let Ok(metadata) = std::fs::symlink_metadata(target) else {
+                    return Ok(());
+                };
+                level.log(Level::Verbose, format!("Removing {}", target.display()));
+                if metadata.is_dir() {
+                    if !source.is_dir() {
+                        return Err(anyhow!(
+                            "Refusing to remove directory {}, its source {} is gone so the files dotty deployed there are unknown",
+                            target.display(),
+                            source.display()
+                        ));
+                    }
+                    remove_deployed_dir(source, target, *sudo)?;
+                } else {
+                    remove_path(target, *sudo)?;
+                }
             }
         }
 
@@ -619,6 +4396,29 @@ impl Action {
     }
 }
 
+/// Turns a permission denied error into a hint about `sudo`, other errors are passed through
+fn permission_denied(error: io::Error, path: &Path, doing: &str) -> anyhow::Error {
+    if error.kind() != io::ErrorKind::PermissionDenied {
+        return error.into();
+    }
+
+    let root_owned = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .and_then(|ancestor| std::fs::metadata(ancestor).ok())
+        .is_some_and(|metadata| metadata.uid() == 0);
+    let hint = if root_owned {
+        "it is owned by root, set sudo = true for this file"
+    } else {
+        "set sudo = true for this file or check ownership"
+    };
+
+    anyhow::Error::new(error).context(format!(
+        "Permission denied {doing} {}; {hint}",
+        path.display()
+    ))
+}
+
 fn sudo_create_dir_all(path: &Path) -> io::Result<()> {
     let path_str = path.to_str().unwrap();
     let status = Command::new("sudo")
@@ -628,10 +4428,224 @@ fn sudo_create_dir_all(path: &Path) -> io::Result<()> {
         .status()?;
 
     if !status.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Failed to create directory",
-        ));
+        return Err(io::Error::other("Failed to create directory"));
+    }
+
+    Ok(())
+}
+
+/// Runs the process, killing it when it is still running after `timeout` seconds
+fn run_with_timeout(process: &mut Command, command: &str, timeout: u64) -> Result<()> {
+    let mut child = process.spawn()?;
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(DottyError::check_status(command, status)?);
+        }
+        if Instant::now() >= deadline {
+            // The shell does not always exec the command, so it has to be stopped as well.
+            // sudo passes the signal on to the command it runs.
+            let _ = Command::new("pkill")
+                .arg("-TERM")
+                .arg("-P")
+                .arg(child.id().to_string())
+                .status();
+            child.kill()?;
+            child.wait()?;
+            return Err(anyhow!("`{command}` timed out after {timeout}s"));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn exclusions(patterns: &[Box<str>]) -> Result<globset::GlobSet> {
+    let mut builder = globset::GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = globset::GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .context(format!("Invalid exclude pattern {pattern}"))?;
+        builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+/// Copies the files of `source` that are missing or differ in `target`, leaving out entries
+/// whose path relative to the copied directory or whose name matches `exclude`. With `mirror`
+/// entries only in `target` are deleted, except excluded ones
+fn sync_dir(
+    source: &Path,
+    target: &Path,
+    relative: &Path,
+    exclude: &globset::GlobSet,
+    mirror: bool,
+    level: Level,
+) -> Result<()> {
+    if std::fs::symlink_metadata(target).is_ok_and(|metadata| !metadata.is_dir()) {
+        std::fs::remove_file(target).map_err(|err| permission_denied(err, target, "removing"))?;
+    }
+    std::fs::create_dir_all(target).map_err(|err| permission_denied(err, target, "creating"))?;
+
+    let mut copied = HashSet::new();
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        let relative = relative.join(entry.file_name());
+        if exclude.is_match(&relative) || exclude.is_match(entry.file_name()) {
+            continue;
+        }
+        copied.insert(entry.file_name());
+
+        let target = target.join(entry.file_name());
+        if entry.path().is_dir() {
+            sync_dir(&entry.path(), &target, &relative, exclude, mirror, level)?;
+        } else if !same_contents(&entry.path(), &target)? {
+            if std::fs::symlink_metadata(&target).is_ok_and(|metadata| metadata.is_dir()) {
+                std::fs::remove_dir_all(&target)
+                    .map_err(|err| permission_denied(err, &target, "removing"))?;
+            }
+            level.log(
+                Level::Trace,
+                format!("Copying {} to {}", entry.path().display(), target.display()),
+            );
+            std::fs::copy(entry.path(), &target)
+                .map_err(|err| permission_denied(err, &target, "writing"))?;
+        }
+    }
+
+    if mirror {
+        for entry in std::fs::read_dir(target)? {
+            let entry = entry?;
+            let relative = relative.join(entry.file_name());
+            if copied.contains(&entry.file_name())
+                || exclude.is_match(&relative)
+                || exclude.is_match(entry.file_name())
+            {
+                continue;
+            }
+            level.log(
+                Level::Verbose,
+                format!("Removing {}", entry.path().display()),
+            );
+            remove_all(&entry.path(), false)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `target` is a regular file with the same contents as `source`
+fn same_contents(source: &Path, target: &Path) -> Result<bool> {
+    let Ok(metadata) = std::fs::symlink_metadata(target) else {
+        return Ok(false);
+    };
+    if !metadata.is_file() || metadata.len() != std::fs::metadata(source)?.len() {
+        return Ok(false);
+    }
+    Ok(std::fs::read(source)? == std::fs::read(target)?)
+}
+
+/// Removes the files `source` would have deployed into `target`, and directories left empty
+fn remove_deployed_dir(source: &Path, target: &Path, sudo: bool) -> Result<()> {
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        let deployed = target.join(entry.file_name());
+        let Ok(metadata) = std::fs::symlink_metadata(&deployed) else {
+            continue;
+        };
+        if metadata.is_dir() {
+            if entry.file_type()?.is_dir() {
+                remove_deployed_dir(&entry.path(), &deployed, sudo)?;
+            }
+        } else {
+            remove_path(&deployed, sudo)?;
+        }
+    }
+
+    if std::fs::read_dir(target)?.next().is_none() {
+        remove_path(target, sudo)?;
+    }
+    Ok(())
+}
+
+/// Puts a copy made by `Action::Backup` back at `target`, replacing whatever is there now
+pub(crate) fn restore_backup(backup: &Path, target: &Path, sudo: bool) -> Result<()> {
+    remove_all(target, sudo)?;
+    if sudo {
+        // Copies like a backup does, just the other way around
+        sudo_backup(backup, target)?;
+    } else if backup.is_dir() {
+        std::fs::create_dir_all(target)?;
+        fs_extra::dir::copy(backup, target, &CopyOptions::new().content_only(true))?;
+    } else {
+        std::fs::create_dir_all(target.parent().unwrap())?;
+        std::fs::copy(backup, target)?;
+    }
+    Ok(())
+}
+
+/// Removes a file, link or directory with everything in it, doing nothing if it is not there
+pub(crate) fn remove_all(path: &Path, sudo: bool) -> Result<()> {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if sudo {
+        let status = Command::new("sudo")
+            .arg("rm")
+            .arg("-rf")
+            .arg(path)
+            .status()?;
+        DottyError::check_status(&format!("sudo rm -rf {}", path.display()), status)?;
+    } else if metadata.is_dir() {
+        std::fs::remove_dir_all(path).map_err(|err| permission_denied(err, path, "removing"))?;
+    } else {
+        std::fs::remove_file(path).map_err(|err| permission_denied(err, path, "removing"))?;
+    }
+    Ok(())
+}
+
+/// Removes a file, link or empty directory
+fn remove_path(path: &Path, sudo: bool) -> Result<()> {
+    let is_dir = std::fs::symlink_metadata(path)?.is_dir();
+    if sudo {
+        let status = Command::new("sudo")
+            .arg(if is_dir { "rmdir" } else { "rm" })
+            .arg(path)
+            .status()?;
+        if !status.success() {
+            return Err(anyhow!("Failed to remove {}", path.display()));
+        }
+    } else if is_dir {
+        std::fs::remove_dir(path).map_err(|err| permission_denied(err, path, "removing"))?;
+    } else {
+        std::fs::remove_file(path).map_err(|err| permission_denied(err, path, "removing"))?;
+    }
+    Ok(())
+}
+
+fn sudo_chmod(path: &Path, mode: u32) -> io::Result<()> {
+    let path_str = path.to_str().unwrap();
+    let status = Command::new("sudo")
+        .arg("chmod")
+        .arg(format!("{mode:04o}"))
+        .arg(path_str)
+        .status()?;
+
+    if !status.success() {
+        return Err(io::Error::other("Failed to change file mode"));
+    }
+
+    Ok(())
+}
+
+fn sudo_chown(path: &Path, owner: &str) -> io::Result<()> {
+    let status = Command::new("sudo")
+        .arg("chown")
+        .arg("-R")
+        .arg(owner)
+        .arg(path)
+        .status()?;
+
+    if !status.success() {
+        return Err(io::Error::other("Failed to change the owner"));
     }
 
     Ok(())
@@ -647,12 +4661,20 @@ fn sudo_copy_file(source: &Path, target: &Path) -> io::Result<()> {
         .status()?;
 
     if !status.success() {
-        return Err(io::Error::new(io::ErrorKind::Other, "Failed to copy file"));
+        return Err(io::Error::other("Failed to copy file"));
     }
 
     Ok(())
 }
 
+fn sudo_read(path: &Path) -> io::Result<Vec<u8>> {
+    let output = Command::new("sudo").arg("cat").arg(path).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("Failed to read file"));
+    }
+    Ok(output.stdout)
+}
+
 fn sudo_copy_dir(source: &Path, target: &Path) -> io::Result<()> {
     let source_str = source.to_str().unwrap();
     let target_str = target.to_str().unwrap();
@@ -667,10 +4689,43 @@ fn sudo_copy_dir(source: &Path, target: &Path) -> io::Result<()> {
 
     let status = cmd.status()?;
     if !status.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Failed to copy directory",
-        ));
+        return Err(io::Error::other("Failed to copy directory"));
+    }
+
+    Ok(())
+}
+
+fn sudo_backup(target: &Path, backup: &Path) -> io::Result<()> {
+    let parent = backup.parent().unwrap();
+    sudo_create_dir_all(parent)?;
+
+    let status = Command::new("sudo")
+        .arg("cp")
+        .arg("-a")
+        .arg(target)
+        .arg(backup)
+        .status()?;
+
+    if !status.success() {
+        return Err(io::Error::other("Failed to back up file"));
+    }
+
+    Ok(())
+}
+
+fn sudo_symlink(source: &Path, target: &Path) -> io::Result<()> {
+    let parent = target.parent().unwrap();
+    sudo_create_dir_all(parent)?;
+
+    let status = Command::new("sudo")
+        .arg("ln")
+        .arg("-sfn")
+        .arg(source)
+        .arg(target)
+        .status()?;
+
+    if !status.success() {
+        return Err(io::Error::other("Failed to create symlink"));
     }
 
     Ok(())
@@ -689,3 +4744,514 @@ fn sudo_copy(source: &Path, target: &Path) -> io::Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `files` into a fresh directory named after `test` and reads the first one as the
+    /// root config
+    fn read(test: &str, files: &[(&str, &str)]) -> Result<Config, DottyError> {
+        let dir = std::env::temp_dir().join(format!("dotty-{test}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        crate::read_config(&dir.join(files[0].0), &[], Level::Quiet)
+    }
+
+    fn sequence(values: &[i64]) -> TemplateValue {
+        let values = values
+            .iter()
+            .map(|value| TemplateValue::Value(value.to_string().into()));
+        TemplateValue::Sequence(values.collect())
+    }
+
+    fn runs_hook(changes: &[Change], hook: &str) -> bool {
+        changes
+            .iter()
+            .any(|change| matches!(change, Change::RawCommand { origin, .. } if &**origin == hook))
+    }
+
+    #[test]
+    fn reenabled_hook_that_never_ran_runs() {
+        let disabled = read(
+            "disabled-hook",
+            &[(
+                "dotty.toml",
+                "[hooks.once.setup]\ncommand = \"true\"\nenabled = false\n",
+            )],
+        )
+        .unwrap();
+        let state = crate::applied_state(&disabled, &Config::default(), &[], &[]).unwrap();
+        assert!(!state.hooks.once.contains_key("setup"));
+
+        let enabled = read(
+            "enabled-hook",
+            &[("dotty.toml", "[hooks.once.setup]\ncommand = \"true\"\n")],
+        )
+        .unwrap();
+        assert!(runs_hook(&enabled.diff(state).unwrap(), "setup"));
+    }
+
+    #[test]
+    fn disabled_hook_keeps_its_state() {
+        let enabled = read(
+            "ran-hook",
+            &[("dotty.toml", "[hooks.once.setup]\ncommand = \"true\"\n")],
+        )
+        .unwrap();
+        let ran = crate::applied_state(&enabled, &Config::default(), &[], &[]).unwrap();
+
+        let disabled = read(
+            "ran-hook-disabled",
+            &[(
+                "dotty.toml",
+                "[hooks.once.setup]\ncommand = \"false\"\nenabled = false\n",
+            )],
+        )
+        .unwrap();
+        let state = crate::applied_state(&disabled, &ran, &[], &[]).unwrap();
+        assert_eq!(state.hooks.once["setup"].command, "true".into());
+        assert!(!runs_hook(&enabled.diff(state).unwrap(), "setup"));
+    }
+
+    #[test]
+    fn equal_sequences_are_equal() {
+        assert_eq!(sequence(&[1, 2, 3]), sequence(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn reordered_sequences_differ() {
+        assert_ne!(sequence(&[1, 2]), sequence(&[2, 1]));
+        assert_ne!(sequence(&[2, 1]), sequence(&[1, 2]));
+    }
+
+    #[test]
+    fn sequences_with_duplicates_differ() {
+        assert_ne!(sequence(&[1, 1, 2]), sequence(&[1, 2]));
+        assert_ne!(sequence(&[1, 2]), sequence(&[1, 1, 2]));
+        assert_ne!(sequence(&[1, 1, 2]), sequence(&[1, 2, 2]));
+    }
+
+    #[test]
+    fn subset_sequences_differ() {
+        assert_ne!(sequence(&[1, 2]), sequence(&[1, 2, 3]));
+        assert_ne!(sequence(&[1, 2, 3]), sequence(&[1, 2]));
+    }
+
+    /// A root importing `mid.toml`, which imports `leaf.toml`
+    fn import_chain(test: &str, root: &str, mid: &str, leaf: &str) -> Config {
+        read(
+            test,
+            &[
+                (
+                    "dotty.toml",
+                    &format!("[module]\nimport = [\"mid.toml\"]\n\n{root}"),
+                ),
+                (
+                    "mid.toml",
+                    &format!("[module]\nimport = [\"leaf.toml\"]\n\n{mid}"),
+                ),
+                ("leaf.toml", leaf),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn settings_come_from_every_level_of_imports() {
+        let config = import_chain(
+            "chain",
+            "",
+            "[dotty]\ndefault_priority = 20\n",
+            "[dotty]\nbackup_dir = \"/tmp/backups\"\nstrict_env = true\n\n[managers.leaf]\n",
+        );
+        assert_eq!(config.dotty.backup_dir.as_deref(), Some("/tmp/backups"));
+        assert!(config.dotty.strict_env);
+        assert_eq!(config.dotty.default_priority, 20);
+        assert!(config.managers.contains_key("leaf"));
+    }
+
+    #[test]
+    fn importing_config_wins_for_settings() {
+        let config = import_chain(
+            "chain-override",
+            "[dotty]\ndefault_priority = 30\n",
+            "[dotty]\ndefault_priority = 20\nbackup_dir = \"/tmp/mid\"\n",
+            "[dotty]\ndefault_priority = 10\nbackup_dir = \"/tmp/leaf\"\n",
+        );
+        assert_eq!(config.dotty.default_priority, 30);
+        assert_eq!(config.dotty.backup_dir.as_deref(), Some("/tmp/mid"));
+    }
+
+    #[test]
+    fn setting_lists_are_combined_across_imports() {
+        let config = import_chain(
+            "chain-lists",
+            "[dotty]\nexclude = [\"root\"]\n",
+            "[dotty]\nexclude = [\"mid\"]\n",
+            "[dotty]\nexclude = [\"leaf\"]\n",
+        );
+        let mut exclude = config.dotty.exclude.clone();
+        exclude.sort();
+        assert_eq!(exclude, ["leaf".into(), "mid".into(), "root".into()]);
+    }
+
+    /// A root with `[dotty]` set to `settings` importing `a.toml` and then `b.toml`
+    fn siblings(test: &str, settings: &str, a: &str, b: &str) -> Result<Config, DottyError> {
+        read(
+            test,
+            &[
+                (
+                    "dotty.toml",
+                    &format!(
+                        "[dotty]\n{settings}\n\n[module]\nimport = [\"a.toml\", \"b.toml\"]\n"
+                    ),
+                ),
+                ("a.toml", a),
+                ("b.toml", b),
+            ],
+        )
+    }
+
+    fn manager(name: &str, add: &str) -> String {
+        format!("[managers.{name}]\nadd = \"{add} #:?\"\n\n")
+    }
+
+    fn add_command(config: &Config, manager: &str) -> Commands {
+        config.managers[manager].add.clone().unwrap()
+    }
+
+    fn template(config: &Config, key: &str) -> TemplateValue {
+        config.template.0[key].clone()
+    }
+
+    #[test]
+    fn override_policy_keeps_the_last_module() {
+        let a = manager("m", "echo a") + "[template]\nname = \"a\"\n";
+        let b = manager("m", "echo b") + "[template]\nname = \"b\"\n";
+        let config = siblings("override", "", &a, &b).unwrap();
+        assert_eq!(add_command(&config, "m"), "echo b #:?".into());
+        assert_eq!(template(&config, "name"), TemplateValue::Value("b".into()));
+    }
+
+    #[test]
+    fn first_wins_policy_keeps_the_first_module() {
+        let a = manager("m", "echo a") + "[template]\nname = \"a\"\n";
+        let b = manager("m", "echo b") + "[template]\nname = \"b\"\n";
+        let config = siblings("first-wins", "merge = \"first-wins\"", &a, &b).unwrap();
+        assert_eq!(add_command(&config, "m"), "echo a #:?".into());
+        assert_eq!(template(&config, "name"), TemplateValue::Value("a".into()));
+    }
+
+    #[test]
+    fn first_wins_policy_keeps_the_root_config() {
+        let config = read(
+            "first-wins-root",
+            &[
+                (
+                    "dotty.toml",
+                    &format!(
+                        "[dotty]\nmerge = \"first-wins\"\n\n[module]\nimport = [\"a.toml\"]\n\n{}",
+                        manager("m", "echo root")
+                    ),
+                ),
+                ("a.toml", &manager("m", "echo a")),
+            ],
+        )
+        .unwrap();
+        assert_eq!(add_command(&config, "m"), "echo root #:?".into());
+    }
+
+    #[test]
+    fn error_policy_rejects_conflicting_managers() {
+        let a = manager("m", "echo a");
+        let b = manager("m", "echo b");
+        assert!(siblings("error", "merge = \"error\"", &a, &b).is_err());
+        assert!(siblings("strict-merge", "strict_merge = true", &a, &b).is_err());
+    }
+
+    #[test]
+    fn error_policy_rejects_conflicting_template_values() {
+        let a = "[template]\nname = \"a\"\n";
+        let b = "[template]\nname = \"b\"\n";
+        assert!(siblings("error-template", "merge = \"error\"", a, b).is_err());
+    }
+
+    #[test]
+    fn non_conflicting_siblings_are_combined() {
+        let a = manager("a", "echo a") + "[template]\ncolors.bg = \"000000\"\n";
+        let b = manager("b", "echo b") + "[template]\ncolors.fg = \"ffffff\"\n";
+        for (test, settings) in [
+            ("siblings-override", ""),
+            ("siblings-first-wins", "merge = \"first-wins\""),
+            ("siblings-error", "merge = \"error\""),
+        ] {
+            let config = siblings(test, settings, &a, &b).unwrap();
+            assert!(config.managers.contains_key("a") && config.managers.contains_key("b"));
+            let TemplateValue::Mapping(colors) = template(&config, "colors") else {
+                panic!("colors is not a mapping");
+            };
+            assert_eq!(colors["bg"], TemplateValue::Value("000000".into()));
+            assert_eq!(colors["fg"], TemplateValue::Value("ffffff".into()));
+        }
+    }
+
+    #[test]
+    fn conflicting_dotted_keys_follow_the_merge_policy() {
+        let a = "[template]\ncolors.bg = \"000000\"\ncolors.fg = \"ffffff\"\n";
+        let b = "[template]\ncolors.bg = \"1d2021\"\n";
+        for (test, settings, bg) in [
+            ("dotted-override", "", "1d2021"),
+            ("dotted-first-wins", "merge = \"first-wins\"", "000000"),
+        ] {
+            let config = siblings(test, settings, a, b).unwrap();
+            let TemplateValue::Mapping(colors) = template(&config, "colors") else {
+                panic!("colors is not a mapping");
+            };
+            assert_eq!(colors["bg"], TemplateValue::Value(bg.into()));
+            assert_eq!(colors["fg"], TemplateValue::Value("ffffff".into()));
+        }
+        assert!(siblings("dotted-error", "merge = \"error\"", a, b).is_err());
+    }
+
+    #[test]
+    fn hooks_with_the_same_command_have_different_ids() {
+        let config = read(
+            "hook-ids",
+            &[(
+                "dotty.toml",
+                "[hooks.once.a]\ncommand = \"make\"\n\n[hooks.once.b]\ncommand = \"make\"\n",
+            )],
+        )
+        .unwrap();
+        let ids = config
+            .diff(Config::default())
+            .unwrap()
+            .iter()
+            .map(Change::id)
+            .collect::<HashSet<_>>();
+        assert_eq!(ids.len(), 2);
+    }
+
+    fn package(spec: &str) -> Result<Package> {
+        Package::try_from(Box::from(spec))
+    }
+
+    #[test]
+    fn scoped_packages_keep_their_scope() {
+        let node = package("@types/node").unwrap();
+        assert_eq!((&*node.name, node.version), ("@types/node", None));
+
+        let pinned = package("@types/node@20").unwrap();
+        assert_eq!(&*pinned.name, "@types/node");
+        assert_eq!(pinned.version.as_deref(), Some("20"));
+    }
+
+    #[test]
+    fn packages_need_a_name_and_version() {
+        assert!(package("").is_err());
+        assert!(package("@").is_err());
+        assert!(package("git@").is_err());
+        assert!(package("@types/node@").is_err());
+        assert!(read(
+            "empty-version",
+            &[("dotty.toml", "[managers.m]\n\n[packages]\nm = [\"git@\"]\n")]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn removals_only_apply_to_the_modules_own_imports() {
+        let config = import_chain(
+            "removals",
+            &manager("root", "echo root"),
+            "[remove]\nmanagers = [\"root\", \"leaf\"]\n",
+            &manager("leaf", "echo leaf"),
+        );
+        assert!(config.managers.contains_key("root"));
+        assert!(!config.managers.contains_key("leaf"));
+    }
+
+    #[test]
+    fn template_values_named_file_are_plain_values() {
+        let config = read(
+            "file-key",
+            &[("dotty.toml", "[template.editor]\nfile = \"nvim\"\n")],
+        )
+        .unwrap();
+        let TemplateValue::Mapping(editor) = template(&config, "editor") else {
+            panic!("editor is not a mapping");
+        };
+        assert_eq!(editor["file"], TemplateValue::Value("nvim".into()));
+    }
+
+    #[test]
+    fn template_data_is_loaded_from_files() {
+        let config = read(
+            "data-file",
+            &[
+                (
+                    "dotty.toml",
+                    "[template.colors]\n\"$file\" = \"colors.toml\"\n",
+                ),
+                ("colors.toml", "bg = \"000000\"\n"),
+            ],
+        )
+        .unwrap();
+        let TemplateValue::Mapping(colors) = template(&config, "colors") else {
+            panic!("colors is not a mapping");
+        };
+        assert_eq!(colors["bg"], TemplateValue::Value("000000".into()));
+    }
+
+    #[test]
+    fn template_data_referring_to_itself_is_an_error() {
+        let err = read(
+            "data-cycle",
+            &[
+                ("dotty.toml", "[template.a]\n\"$file\" = \"a.toml\"\n"),
+                ("a.toml", "[b]\n\"$file\" = \"b.toml\"\n"),
+                ("b.toml", "[a]\n\"$file\" = \"a.toml\"\n"),
+            ],
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("refers back to itself"));
+    }
+
+    #[test]
+    fn profiles_with_the_same_name_are_combined() {
+        let a = "[profiles.work.packages]\nm = [\"a\"]\n\n[profiles.work.hooks.once.a]\ncommand = \"true\"\n";
+        let b = "[profiles.work.packages]\nm = [\"b\"]\n\n[profiles.work.hooks.once.b]\ncommand = \"true\"\n";
+        let mut config: Config = toml::from_str(a).unwrap();
+        config
+            .combine(toml::from_str(b).unwrap(), MergePolicy::Override)
+            .unwrap();
+        let work = &config.profiles["work"];
+        assert_eq!(work.packages["m"].len(), 2);
+        assert!(work.hooks.once.contains_key("a") && work.hooks.once.contains_key("b"));
+    }
+
+    #[test]
+    fn secrets_are_redacted_from_output() {
+        let config = read(
+            "secrets",
+            &[(
+                "dotty.toml",
+                "[secrets]\ntoken = { command = \"echo hunter2\" }\n",
+            )],
+        )
+        .unwrap();
+        config.secrets().unwrap();
+
+        let verbose = format!("{:?}", std::process::Command::new("curl").arg("hunter2"));
+        let err = anyhow!("Command `curl hunter2` failed").context("in hook upload");
+        for printed in [verbose, format!("{err:?}")] {
+            let printed = crate::verbosity::redact(&printed);
+            assert!(!printed.contains("hunter2"));
+            assert!(printed.contains("<secret token>"));
+        }
+    }
+
+    /// A source and a target directory for `sync_dir`, named after `test`
+    fn sync_dirs(test: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("dotty-{test}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (source, target) = (dir.join("source"), dir.join("target"));
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::create_dir_all(&target).unwrap();
+        (source, target)
+    }
+
+    fn sync(source: &Path, target: &Path, exclude: &[&str], mirror: bool) {
+        let mut globs = globset::GlobSetBuilder::new();
+        for pattern in exclude {
+            globs.add(globset::Glob::new(pattern).unwrap());
+        }
+        let exclude = globs.build().unwrap();
+        sync_dir(
+            source,
+            target,
+            Path::new(""),
+            &exclude,
+            mirror,
+            Level::Quiet,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn sync_only_copies_changed_files() {
+        let (source, target) = sync_dirs("sync-changed");
+        std::fs::write(source.join("same"), "same").unwrap();
+        std::fs::write(source.join("changed"), "old").unwrap();
+        sync(&source, &target, &[], false);
+
+        let untouched = UNIX_EPOCH + Duration::from_secs(1000);
+        std::fs::File::options()
+            .write(true)
+            .open(target.join("same"))
+            .unwrap()
+            .set_modified(untouched)
+            .unwrap();
+        std::fs::write(source.join("changed"), "new").unwrap();
+        sync(&source, &target, &[], false);
+
+        let modified = std::fs::metadata(target.join("same")).unwrap().modified();
+        assert_eq!(modified.unwrap(), untouched);
+        assert_eq!(
+            std::fs::read_to_string(target.join("changed")).unwrap(),
+            "new"
+        );
+    }
+
+    #[test]
+    fn mirror_prunes_entries_missing_from_the_source() {
+        let (source, target) = sync_dirs("sync-mirror");
+        std::fs::write(source.join("kept"), "kept").unwrap();
+        std::fs::write(target.join("extra"), "extra").unwrap();
+        std::fs::create_dir_all(target.join("extra-dir/nested")).unwrap();
+
+        sync(&source, &target, &[], false);
+        assert!(target.join("extra").exists() && target.join("extra-dir").exists());
+
+        sync(&source, &target, &[], true);
+        assert!(target.join("kept").exists());
+        assert!(!target.join("extra").exists() && !target.join("extra-dir").exists());
+    }
+
+    #[test]
+    fn excluded_entries_survive_mirror() {
+        let (source, target) = sync_dirs("sync-exclude");
+        std::fs::write(source.join("config"), "config").unwrap();
+        std::fs::write(source.join("source.log"), "source").unwrap();
+        std::fs::write(target.join("target.log"), "target").unwrap();
+
+        sync(&source, &target, &["*.log"], true);
+        assert!(target.join("config").exists());
+        assert!(target.join("target.log").exists());
+        assert!(!target.join("source.log").exists());
+    }
+
+    #[test]
+    fn files_and_directories_replace_each_other() {
+        let (source, target) = sync_dirs("sync-replace");
+        std::fs::create_dir_all(source.join("now-dir")).unwrap();
+        std::fs::write(source.join("now-dir/file"), "file").unwrap();
+        std::fs::write(source.join("now-file"), "file").unwrap();
+        std::fs::write(target.join("now-dir"), "was a file").unwrap();
+        std::fs::create_dir_all(target.join("now-file/nested")).unwrap();
+
+        sync(&source, &target, &[], false);
+        assert_eq!(
+            std::fs::read_to_string(target.join("now-dir/file")).unwrap(),
+            "file"
+        );
+        assert_eq!(
+            std::fs::read_to_string(target.join("now-file")).unwrap(),
+            "file"
+        );
+    }
+}
@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+use std::process::ExitStatus;
+
+/// Errors returned by the library, the ones callers may want to react to have their own variant
+/// and everything else is kept as `Other`.
+///
+/// Errors given more context on the way up stay `Other`, their typed cause can still be found
+/// with `anyhow::Error::downcast_ref` on the chain.
+#[derive(Debug, thiserror::Error)]
+pub enum DottyError {
+    #[error("Manager {manager} not found")]
+    ManagerNotFound { manager: Box<str> },
+    #[error("Could not render template {}: {reason}", template.display())]
+    TemplateRender { template: PathBuf, reason: String },
+    #[error(
+        "Files {} all deploy to {} with priority {priority}, give them different priorities",
+        sources.join(", "),
+        target.display()
+    )]
+    DuplicateTarget {
+        target: PathBuf,
+        sources: Vec<Box<str>>,
+        priority: i32,
+    },
+    #[error("Circular import {cycle}")]
+    CyclicImport { cycle: Box<str> },
+    /// `code` is `None` when the command was killed by a signal
+    #[error("`{command}` failed{}", code.map(|code| format!(" with exit code {code}")).unwrap_or_default())]
+    CommandFailed {
+        command: Box<str>,
+        code: Option<i32>,
+    },
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl DottyError {
+    /// Errors unless the command exited successfully
+    pub(crate) fn check_status(command: &str, status: ExitStatus) -> Result<(), Self> {
+        if status.success() {
+            return Ok(());
+        }
+        Err(Self::CommandFailed {
+            command: command.into(),
+            code: status.code(),
+        })
+    }
+}
+
+/// Unwraps errors that are a `DottyError` without added context, so their variant can be matched
+impl From<anyhow::Error> for DottyError {
+    fn from(err: anyhow::Error) -> Self {
+        if err.chain().nth(1).is_some() {
+            return Self::Other(err);
+        }
+        err.downcast().unwrap_or_else(Self::Other)
+    }
+}
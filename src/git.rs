@@ -0,0 +1,209 @@
+//! Modules imported from git repositories, written as `git+<url>?ref=<ref>#<path>`.
+//! Each repository is cloned once into the cache directory and only pulled by `dotty update`.
+//! Repositories imported with a `minisign_pubkey` are checked against their signed manifest.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::config::GIT_PREFIX;
+use crate::error::DottyError;
+
+/// The module file loaded when the import has no `#path`
+const DEFAULT_MODULE: &str = "dotty.toml";
+/// Hashes of every file in a repository imported with a `minisign_pubkey`, signed with the key
+const MANIFEST: &str = "dotty.manifest";
+
+pub struct GitModule {
+    url: Box<str>,
+    reference: Option<Box<str>>,
+    path: Box<str>,
+}
+
+impl GitModule {
+    pub fn parse(import: &str) -> Result<Self> {
+        let rest = import
+            .strip_prefix(GIT_PREFIX)
+            .ok_or_else(|| anyhow!("{import} is not a git import"))?;
+        let (rest, path) = rest.split_once('#').unwrap_or((rest, DEFAULT_MODULE));
+        let (url, reference) = match rest.split_once("?ref=") {
+            Some((url, reference)) => (url, Some(reference)),
+            None => (rest, None),
+        };
+        if url.is_empty() || path.is_empty() || reference.is_some_and(str::is_empty) {
+            return Err(anyhow!(
+                "Invalid git import {import}, expected git+<url>?ref=<ref>#<path>"
+            ));
+        }
+        if Path::new(path).is_absolute() || path.split('/').any(|part| part == "..") {
+            return Err(anyhow!(
+                "The path of git import {import} must stay inside the repository"
+            ));
+        }
+
+        Ok(Self {
+            url: url.into(),
+            reference: reference.map(Into::into),
+            path: path.into(),
+        })
+    }
+
+    /// Where the repository is cloned, keyed by the url and ref so each pair gets its own clone
+    fn checkout(&self) -> Result<PathBuf> {
+        let cache = match std::env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(
+                std::env::var_os("HOME").context("Neither XDG_CACHE_HOME nor HOME is set")?,
+            )
+            .join(".cache"),
+        };
+        let key = format!("{}#{}", self.url, self.reference.as_deref().unwrap_or(""));
+        let hash = format!("{:x}", Sha256::digest(key.as_bytes()));
+        Ok(cache.join("dotty/modules").join(&hash[..16]))
+    }
+
+    /// Clones the repository if it is not cached yet, returning the path of the module file
+    pub fn fetch(&self) -> Result<PathBuf> {
+        let checkout = self.checkout()?;
+        if !checkout.join(".git").exists() {
+            if checkout.exists() {
+                std::fs::remove_dir_all(&checkout)?;
+            }
+            std::fs::create_dir_all(checkout.parent().unwrap())?;
+            let mut command = Command::new("git");
+            command.args(["clone", "--quiet"]);
+            if let Some(reference) = &self.reference {
+                command.args(["--branch", reference]);
+            }
+            command.arg(&*self.url).arg(&checkout);
+            let status = command.status().context("Could not run git")?;
+            DottyError::check_status(&format!("git clone {}", self.url), status)?;
+        }
+        Ok(checkout.join(&*self.path))
+    }
+
+    /// Updates an already cached clone to the latest commit of its ref
+    pub fn pull(&self) -> Result<()> {
+        let checkout = self.checkout()?;
+        if !checkout.join(".git").exists() {
+            self.fetch()?;
+            return Ok(());
+        }
+        let reference = self.reference.as_deref().unwrap_or("HEAD");
+        for args in [
+            vec!["fetch", "--quiet", "origin", reference],
+            vec!["reset", "--quiet", "--hard", "FETCH_HEAD"],
+        ] {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(&checkout)
+                .args(&args)
+                .status()
+                .context("Could not run git")?;
+            DottyError::check_status(&format!("git {}", args.join(" ")), status)?;
+        }
+        Ok(())
+    }
+
+    /// Checks every file of the clone against the signed `dotty.manifest` at its root, so imports,
+    /// file sources and template data loaded from the repository are all covered
+    pub fn verify(&self, pubkey: &str) -> Result<()> {
+        let checkout = self.checkout()?;
+        let manifest = checkout.join(MANIFEST);
+        let signature = checkout.join(format!("{MANIFEST}.minisig"));
+        if !manifest.exists() || !signature.exists() {
+            return Err(anyhow!(
+                "No signed manifest found, expected {} and {}",
+                manifest.display(),
+                signature.display()
+            ));
+        }
+        verify_signature(&manifest, &signature, pubkey)?;
+
+        let mut listed = HashMap::new();
+        for line in std::fs::read_to_string(&manifest)?.lines() {
+            let Some((hash, path)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            // As printed by `sha256sum`, which may mark binary files with `*`
+            let path = path
+                .trim_start()
+                .trim_start_matches('*')
+                .trim_start_matches("./");
+            listed.insert(PathBuf::from(path), hash.to_lowercase());
+        }
+
+        let mut files = Vec::new();
+        repository_files(&checkout, &mut files)?;
+        for file in files {
+            let relative = file.strip_prefix(&checkout)?;
+            if relative == Path::new(MANIFEST)
+                || relative == Path::new(&format!("{MANIFEST}.minisig"))
+            {
+                continue;
+            }
+            let expected = listed
+                .remove(relative)
+                .ok_or_else(|| anyhow!("{} is not in the signed manifest", relative.display()))?;
+            let actual = format!("{:x}", Sha256::digest(std::fs::read(&file)?));
+            if actual != expected {
+                return Err(anyhow!(
+                    "{} does not match the signed manifest",
+                    relative.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fails with an explanation when the `minisign` command is not installed, checked before
+/// fetching modules that need it
+pub fn require_minisign() -> Result<()> {
+    match Command::new("minisign").arg("-v").output() {
+        Err(err) if err.kind() == ErrorKind::NotFound => Err(anyhow!(
+            "The minisign command is needed to verify modules with a `minisign_pubkey`, but it is not installed"
+        )),
+        Err(err) => Err(err).context("Could not run minisign"),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// Checks `file` against its `signature` with the `minisign` command
+fn verify_signature(file: &Path, signature: &Path, pubkey: &str) -> Result<()> {
+    let output = Command::new("minisign")
+        .args(["-V", "-q", "-P", pubkey, "-m"])
+        .arg(file)
+        .arg("-x")
+        .arg(signature)
+        .output()
+        .context("Could not run minisign to verify the signature")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Bad signature at {}: {}",
+            signature.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Every file under `dir`, leaving out the `.git` directory
+fn repository_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            repository_files(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
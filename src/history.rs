@@ -0,0 +1,89 @@
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// A successful run, stored as one JSON line in the history file
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Entry {
+    /// Unix timestamp in seconds
+    pub timestamp: u64,
+    pub command: Box<str>,
+    pub changes: Vec<Box<str>>,
+}
+
+impl Entry {
+    pub fn now(command: &str, changes: Vec<Box<str>>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        Self {
+            timestamp,
+            command: command.into(),
+            changes,
+        }
+    }
+
+    /// The timestamp as `YYYY-MM-DD HH:MM:SS` in UTC
+    pub fn time(&self) -> String {
+        format_timestamp(self.timestamp)
+    }
+}
+
+/// A unix timestamp in seconds as `YYYY-MM-DD HH:MM:SS` in UTC
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+
+    // Converts days since the epoch to a civil date, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+pub fn append(path: &Path, entry: &Entry) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(format!("Could not open history file {}", path.display()))?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+/// Reads the last `count` entries, oldest first
+pub fn read(path: &Path, count: usize) -> Result<Vec<Entry>> {
+    let content = std::fs::read_to_string(path)
+        .context(format!("Could not read history file {}", path.display()))?;
+    let lines = content.lines().filter(|line| !line.trim().is_empty());
+    let entries = lines
+        .map(|line| serde_json::from_str(line).context(format!("Invalid history entry {line}")))
+        .collect::<Result<Vec<Entry>>>()?;
+    let skip = entries.len().saturating_sub(count);
+    Ok(entries.into_iter().skip(skip).collect())
+}
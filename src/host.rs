@@ -0,0 +1,33 @@
+use std::process::Command;
+
+use anyhow::{anyhow, Result};
+
+const OS_RELEASE: &str = "/etc/os-release";
+
+pub fn hostname() -> Result<Box<str>> {
+    if let Ok(hostname) = std::fs::read_to_string("/etc/hostname") {
+        return Ok(hostname.trim().into());
+    }
+
+    let output = Command::new("uname").arg("-n").output()?;
+    if !output.status.success() {
+        return Err(anyhow!("Failed to determine hostname"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().into())
+}
+
+/// The `ID` field of `/etc/os-release`, `None` on systems without one
+pub fn distro() -> Option<Box<str>> {
+    let content = std::fs::read_to_string(OS_RELEASE).ok()?;
+    content.lines().find_map(|line| {
+        line.strip_prefix("ID=")
+            .map(|id| id.trim_matches('"').into())
+    })
+}
+
+/// Whether an executable with this name is in one of the `PATH` directories
+pub fn has_binary(name: &str) -> bool {
+    std::env::var_os("PATH").is_some_and(|paths| {
+        std::env::split_paths(&paths).any(|directory| directory.join(name).is_file())
+    })
+}
@@ -0,0 +1,125 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, Change, Package};
+use crate::verbosity::Level;
+
+/// What the last apply changed, so `dotty rollback` can undo it
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct Journal {
+    /// The state file before the apply, `None` when there was none
+    pub state: Option<String>,
+    /// Packages installed by the apply for each manager, which are never rolled back
+    pub packages: BTreeMap<Box<str>, Vec<Package>>,
+    /// Targets the apply wrote or removed
+    pub files: Vec<TouchedFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TouchedFile {
+    pub target: PathBuf,
+    /// Targets that did not exist before the apply are removed again
+    pub existed: bool,
+    /// Copy of what the target held before the apply, only made when `backup_dir` is set
+    pub backup: Option<PathBuf>,
+    pub sudo: bool,
+}
+
+impl Journal {
+    /// Records the state at `state_path` and which targets of `changes` exist, before they run
+    pub fn start(state_path: &Path, changes: &[Change]) -> Self {
+        let files = changes
+            .iter()
+            .filter_map(Change::file_target)
+            .map(|(target, sudo)| TouchedFile {
+                target: target.into(),
+                existed: std::fs::symlink_metadata(target).is_ok(),
+                backup: None,
+                sudo,
+            })
+            .collect();
+        Self {
+            state: std::fs::read_to_string(state_path).ok(),
+            packages: BTreeMap::new(),
+            files,
+        }
+    }
+
+    pub fn record_backup(&mut self, target: &Path, backup: &Path) {
+        if let Some(file) = self.files.iter_mut().find(|file| file.target == target) {
+            file.backup = Some(backup.into());
+        }
+    }
+
+    /// Records the packages of every change that did not fail
+    pub fn finish(&mut self, changes: &[Change], failed: &[Change]) {
+        let failed = failed.iter().map(Change::id).collect::<HashSet<_>>();
+        for change in changes {
+            if let Change::AddPackage { manager, packages } = change {
+                if !failed.contains(&change.id()) {
+                    let installed = self.packages.entry(manager.clone()).or_default();
+                    installed.extend(packages.iter().cloned());
+                    installed.sort();
+                }
+            }
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).map_err(|_| {
+            anyhow!(
+                "Nothing to roll back, no apply was recorded at {}",
+                path.display()
+            )
+        })?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn remove(path: &Path) -> Result<()> {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Puts every touched target back the way it was: backups are restored and targets the apply
+    /// created are removed. Returns the targets that were overwritten without a backup
+    pub fn restore_files(&self, level: Level) -> Result<Vec<&Path>> {
+        let mut lost = Vec::new();
+        for file in self.files.iter() {
+            let target = &file.target;
+            match &file.backup {
+                Some(backup) if std::fs::symlink_metadata(backup).is_ok() => {
+                    level.log(
+                        Level::Normal,
+                        format!("[<] {} -> {}", backup.display(), target.display()).purple(),
+                    );
+                    config::restore_backup(backup, target, file.sudo)?;
+                }
+                _ if !file.existed => {
+                    if std::fs::symlink_metadata(target).is_ok() {
+                        level.log(
+                            Level::Normal,
+                            format!("[<] remove {}", target.display()).red(),
+                        );
+                        config::remove_all(target, file.sudo)?;
+                    }
+                }
+                _ => lost.push(target.as_path()),
+            }
+        }
+        Ok(lost)
+    }
+}
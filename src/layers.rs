@@ -0,0 +1,113 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context, Result};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Format {
+    Json,
+    Toml,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Ok(Self::Json),
+            Some("toml") => Ok(Self::Toml),
+            _ => Err(anyhow!(
+                "Unsupported layer format for {}, only .json and .toml layers can be merged",
+                path.display()
+            )),
+        }
+    }
+}
+
+fn layer_path(layer: &str) -> Result<PathBuf> {
+    let layer = shellexpand::tilde(layer);
+    Ok(PathBuf::from_str(&layer)?)
+}
+
+pub fn latest_modified(layers: &[Box<str>]) -> Result<SystemTime> {
+    let mut latest = SystemTime::UNIX_EPOCH;
+    for layer in layers {
+        let modified = std::fs::metadata(layer_path(layer)?)?.modified()?;
+        latest = latest.max(modified);
+    }
+    Ok(latest)
+}
+
+/// Deep merges the given layers in order, later layers overriding earlier ones
+pub fn merge(layers: &[Box<str>]) -> Result<Box<str>> {
+    let paths = layers
+        .iter()
+        .map(|layer| layer_path(layer))
+        .collect::<Result<Vec<_>>>()?;
+    let first = paths.first().ok_or(anyhow!("No layers to merge"))?;
+    let format = Format::from_path(first)?;
+
+    for path in &paths {
+        if Format::from_path(path)? != format {
+            return Err(anyhow!(
+                "Layer {} does not match the format of {}",
+                path.display(),
+                first.display()
+            ));
+        }
+    }
+
+    match format {
+        Format::Json => {
+            let mut merged = serde_json::Value::Null;
+            for path in &paths {
+                let content = std::fs::read_to_string(path)?;
+                let layer = serde_json::from_str(&content)
+                    .context(format!("in layer {}", path.display()))?;
+                merge_json(&mut merged, layer);
+            }
+            Ok(serde_json::to_string_pretty(&merged)?.into())
+        }
+        Format::Toml => {
+            let mut merged = toml::Value::Table(toml::Table::new());
+            for path in &paths {
+                let content = std::fs::read_to_string(path)?;
+                let layer =
+                    toml::from_str(&content).context(format!("in layer {}", path.display()))?;
+                merge_toml(&mut merged, layer);
+            }
+            Ok(toml::to_string(&merged)?.into())
+        }
+    }
+}
+
+fn merge_json(base: &mut serde_json::Value, layer: serde_json::Value) {
+    match (base, layer) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(current) => merge_json(current, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer,
+    }
+}
+
+fn merge_toml(base: &mut toml::Value, layer: toml::Value) {
+    match (base, layer) {
+        (toml::Value::Table(base), toml::Value::Table(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(current) => merge_toml(current, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer,
+    }
+}
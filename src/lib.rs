@@ -0,0 +1,556 @@
+#![feature(exit_status_error)]
+
+//! Dotty as a library, for tools that want to plan or apply a config without the CLI.
+//! Nothing here prints unless the given `Level` asks for it, results are returned instead.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use colored::Colorize;
+use serde::Serialize;
+
+pub use config::{
+    Action, Change, Config, MergePolicy, Package, PackageQuery, Preset, Skip, SudoStrategy,
+};
+pub use error::DottyError;
+pub use verbosity::Level;
+
+pub mod config;
+mod error;
+#[cfg(feature = "git")]
+mod git;
+pub mod history;
+mod host;
+pub mod journal;
+mod layers;
+pub mod resume;
+pub mod shell;
+pub mod state;
+mod sudo;
+pub mod textdiff;
+pub mod verbosity;
+
+/// Where a config is read from, a path of `-` is stdin and an `http://` or `https://` path a URL
+enum Source<'a> {
+    File(&'a Path),
+    Stdin,
+    Url(&'a str),
+}
+
+impl<'a> Source<'a> {
+    fn of(path: &'a Path) -> Self {
+        match path.to_str() {
+            Some("-") => Self::Stdin,
+            Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+                Self::Url(url)
+            }
+            _ => Self::File(path),
+        }
+    }
+
+    fn read(&self) -> Result<String> {
+        match self {
+            Self::File(path) => Ok(std::fs::read_to_string(path)?),
+            Self::Stdin => {
+                let mut content = String::new();
+                std::io::stdin().read_to_string(&mut content)?;
+                Ok(content)
+            }
+            Self::Url(url) => {
+                let output = std::process::Command::new("curl")
+                    .args(["--fail", "--silent", "--show-error", "--location", url])
+                    .output()
+                    .context("Could not run curl to fetch the config")?;
+                if !output.status.success() {
+                    return Err(anyhow!(
+                        "Fetching {url} failed: {}",
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ));
+                }
+                Ok(String::from_utf8(output.stdout)?)
+            }
+        }
+    }
+
+    /// Parses the config and loads its imports. Configs from stdin or a URL have no directory
+    /// to import from, so their relative paths are resolved from the current directory instead
+    fn load(&self) -> Result<Config> {
+        let mut config: Config = toml::from_str(&self.read()?)?;
+        match self {
+            Self::File(path) => config.load_dependencies(path)?,
+            Self::Stdin | Self::Url(_) => {
+                if config.has_imports() {
+                    return Err(anyhow!(
+                        "Configs read from stdin or a URL can not import modules"
+                    ));
+                }
+                config.load_dependencies(Path::new("-"))?;
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Reads a config along with its imports, merging in the selected profiles.
+/// `path` can also be `-` for stdin or an `http://` or `https://` URL, fetched with `curl`.
+pub fn read_config(path: &Path, profiles: &[Box<str>], level: Level) -> Result<Config, DottyError> {
+    level.log(
+        Level::Verbose,
+        format!("Reading config at {}", path.to_string_lossy().blue()),
+    );
+
+    let mut config = Source::of(path).load()?;
+    config.select_profiles(profiles)?;
+    config.resolve_aliases();
+    config.normalize_packages();
+    config.validate()?;
+    Ok(config)
+}
+
+/// Pulls every module imported from git to the latest commit of its ref. Clones are only
+/// updated here so other commands stay reproducible, read the config again to use the result
+#[cfg_attr(not(feature = "git"), allow(unused_variables))]
+pub fn pull_modules(config: &Config, level: Level) -> Result<(), DottyError> {
+    #[cfg(feature = "git")]
+    for module in config.git_modules() {
+        level.log(Level::Verbose, format!("Pulling module {}", module.blue()));
+        git::GitModule::parse(module)?.pull()?;
+    }
+    Ok(())
+}
+
+/// Like `read_config`, but reports every problem found instead of stopping at the first
+pub fn check_config(path: &Path, profiles: &[Box<str>], level: Level) -> Vec<String> {
+    level.log(
+        Level::Verbose,
+        format!("Checking config at {}", path.to_string_lossy().blue()),
+    );
+
+    let mut config = match Source::of(path).load() {
+        Ok(config) => config,
+        Err(err) => return vec![format!("{}: {err:#}", path.display())],
+    };
+    if let Err(err) = config.select_profiles(profiles) {
+        return vec![format!("{err:#}")];
+    }
+    config.resolve_aliases();
+    config.normalize_packages();
+
+    let mut problems = config
+        .check()
+        .into_iter()
+        .map(|problem| format!("{problem:#}"))
+        .collect::<Vec<_>>();
+    problems.sort();
+    problems.dedup();
+    problems
+}
+
+pub fn write_config(path: &Path, config: &Config) -> Result<(), DottyError> {
+    let content = toml::to_string(config).map_err(anyhow::Error::from)?;
+    std::fs::write(path, content).map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// How `execute` runs changes
+#[derive(Debug, Clone, Copy)]
+pub struct ExecuteOptions {
+    /// How many changes sharing a priority run at once
+    pub jobs: usize,
+    /// Keep running the remaining changes when one fails
+    pub keep_going: bool,
+    /// Times failing commands are retried when their manager does not choose
+    pub retries: u32,
+    pub level: Level,
+}
+
+impl Default for ExecuteOptions {
+    fn default() -> Self {
+        Self {
+            jobs: 1,
+            keep_going: false,
+            retries: 0,
+            level: Level::Quiet,
+        }
+    }
+}
+
+/// Receives progress while changes are executed, every method does nothing by default.
+///
+/// `change_started`, `action_started` and `action_finished` are called from the worker running
+/// the change, so changes sharing a priority report concurrently.
+pub trait Reporter: Sync {
+    /// Asked before every destructive action, which is skipped when this returns false.
+    /// All actions of a priority are confirmed before any of them run.
+    fn confirm(&mut self, _action: &Action) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn change_started(&self, _change: &Change) {}
+
+    fn action_started(&self, _action: &Action) {}
+
+    fn action_finished(&self, _action: &Action, _success: bool) {}
+
+    /// Called once every change of a priority is done, in the order the changes were given
+    fn change_finished(
+        &mut self,
+        _change: &Change,
+        _result: &Result<(), DottyError>,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Reports nothing and confirms every action
+impl Reporter for () {}
+
+/// Progress as sent by the `Reporter` implementation of `mpsc::Sender`
+#[derive(Debug, Clone)]
+pub enum Event {
+    ChangeStarted(Change),
+    ActionStarted(Action),
+    ActionFinished { action: Action, success: bool },
+    ChangeFinished { change: Change, success: bool },
+}
+
+/// Sends every event to the receiver, confirming every action.
+/// Events are dropped once the receiver is gone.
+impl Reporter for mpsc::Sender<Event> {
+    fn change_started(&self, change: &Change) {
+        let _ = self.send(Event::ChangeStarted(change.clone()));
+    }
+
+    fn action_started(&self, action: &Action) {
+        let _ = self.send(Event::ActionStarted(action.clone()));
+    }
+
+    fn action_finished(&self, action: &Action, success: bool) {
+        let _ = self.send(Event::ActionFinished {
+            action: action.clone(),
+            success,
+        });
+    }
+
+    fn change_finished(&mut self, change: &Change, result: &Result<(), DottyError>) -> Result<()> {
+        let _ = self.send(Event::ChangeFinished {
+            change: change.clone(),
+            success: result.is_ok(),
+        });
+        Ok(())
+    }
+}
+
+/// Executes the changes in priority order, changes sharing a priority are run in parallel.
+///
+/// Progress and confirmations go through `reporter`.
+/// Changes that failed are only ever returned with `keep_going` or when optional packages fail
+/// to install.
+pub fn execute(
+    changes: Vec<Change>,
+    config: &Config,
+    options: ExecuteOptions,
+    reporter: &mut impl Reporter,
+) -> Result<Executed, DottyError> {
+    let level = options.level;
+    let keep_going = options.keep_going;
+    let mut failed = Vec::new();
+    let mut declined = Vec::new();
+    let mut keep_alive = None;
+    for (priority, group) in groups(changes, config) {
+        // Confirmations are asked up front so prompts are not mixed with parallel output.
+        // Changes to the same manager share a lane so a removal finishes before the re-add.
+        // A change whose actions can not be built, like a template failing to render, fails on
+        // its own when its lane gets to it instead of stopping every other change.
+        let mut lanes: Vec<Vec<(Change, Result<Vec<Action>>)>> = Vec::with_capacity(group.len());
+        let mut manager_lanes: HashMap<Box<str>, usize> = HashMap::new();
+        for change in group {
+            let destructive = change.is_destructive();
+            let mut confirmed = true;
+            let actions = match change.clone().action(config) {
+                Ok(built) => {
+                    let mut actions = Vec::with_capacity(built.len());
+                    for mut action in built {
+                        action.default_retries(options.retries);
+                        if (destructive || action.is_destructive()) && !reporter.confirm(&action)? {
+                            confirmed = false;
+                            break;
+                        }
+                        actions.push(action);
+                    }
+                    Ok(actions)
+                }
+                Err(err) => Err(err),
+            };
+            // A change with a declined action is not run at all, as running the rest of it
+            // could leave things half done
+            if !confirmed {
+                declined.push(change);
+                continue;
+            }
+
+            let lane = match change.manager() {
+                Some(manager) => *manager_lanes.entry(manager.into()).or_insert_with(|| {
+                    lanes.push(Vec::new());
+                    lanes.len() - 1
+                }),
+                None => {
+                    lanes.push(Vec::new());
+                    lanes.len() - 1
+                }
+            };
+            lanes[lane].push((change, actions));
+        }
+
+        let needs_sudo = lanes
+            .iter()
+            .flatten()
+            .filter_map(|(_, actions)| actions.as_ref().ok())
+            .flatten()
+            .any(Action::uses_sudo);
+        if needs_sudo && keep_alive.is_none() && config.sudo_strategy() == SudoStrategy::Refresh {
+            keep_alive = Some(sudo::KeepAlive::start()?);
+        }
+
+        // Workers take lanes in order, so a single job runs everything sequentially
+        let workers = options.jobs.max(1).min(lanes.len());
+        let queue = Mutex::new(lanes.into_iter().enumerate());
+        let stopped = AtomicBool::new(false);
+        let shared = &*reporter;
+        let mut results = std::thread::scope(|scope| {
+            let handles = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| -> Vec<(usize, Change, Result<(), DottyError>)> {
+                        let mut results = Vec::new();
+                        while !stopped.load(Ordering::SeqCst) {
+                            let Some((index, lane)) = queue.lock().unwrap().next() else {
+                                break;
+                            };
+                            for (change, actions) in lane {
+                                shared.change_started(&change);
+                                let result = actions.and_then(|actions| {
+                                    actions.iter().try_for_each(|action| {
+                                        shared.action_started(action);
+                                        let result = action.execute(level);
+                                        shared.action_finished(action, result.is_ok());
+                                        result
+                                    })
+                                });
+                                let result = result.map_err(DottyError::from);
+                                let stop = result.is_err() && !keep_going && !change.is_optional();
+                                results.push((index, change, result));
+                                if stop {
+                                    stopped.store(true, Ordering::SeqCst);
+                                    break;
+                                }
+                            }
+                        }
+                        results
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("Change panicked while executing"))
+                .collect::<Vec<_>>()
+        });
+        results.sort_by_key(|(index, ..)| *index);
+
+        let mut required_failed = false;
+        for (_, change, result) in results {
+            reporter.change_finished(&change, &result)?;
+            if result.is_err() {
+                required_failed |= !change.is_optional();
+                failed.push(change);
+            }
+        }
+        if !keep_going && required_failed {
+            return Err(anyhow!("{} change(s) failed at priority {priority}", failed.len()).into());
+        }
+    }
+    Ok(Executed { failed, declined })
+}
+
+/// The changes `execute` did not get done
+#[derive(Debug, Default)]
+pub struct Executed {
+    pub failed: Vec<Change>,
+    /// Changes not run since the reporter declined one of their actions
+    pub declined: Vec<Change>,
+}
+
+/// Splits the changes into the groups `execute` runs one after another, the changes of a group
+/// share a priority and none of them depends on another
+fn groups(changes: Vec<Change>, config: &Config) -> Vec<(i32, Vec<Change>)> {
+    let mut groups = Vec::new();
+    let mut changes = changes.into_iter().peekable();
+    while let Some(first) = changes.next() {
+        let priority = first.priority(config);
+        let mut group = vec![first];
+        while let Some(change) = changes.next_if(|change| {
+            change.priority(config) == priority
+                && !group
+                    .iter()
+                    .any(|earlier| config.depends_on(change, earlier))
+        }) {
+            group.push(change);
+        }
+        groups.push((priority, group));
+    }
+    groups
+}
+
+/// Changes `execute` runs at the same time
+#[derive(Debug, Clone, Serialize)]
+pub struct Step {
+    pub priority: i32,
+    pub changes: Vec<PlannedChange>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedChange {
+    pub change: Change,
+    /// With commands expanded, as they would be run
+    pub actions: Vec<Action>,
+}
+
+/// What `execute` would do with the changes, in order, without running or confirming anything
+pub fn plan(changes: Vec<Change>, config: &Config) -> Result<Vec<Step>, DottyError> {
+    let mut steps = Vec::new();
+    for (priority, group) in groups(changes, config) {
+        let mut changes = Vec::with_capacity(group.len());
+        for change in group {
+            let actions = change.clone().action(config)?;
+            changes.push(PlannedChange { change, actions });
+        }
+        steps.push(Step { priority, changes });
+    }
+    Ok(steps)
+}
+
+/// The state to write after applying `config` over `old`, leaving out the changes that failed or
+/// were not run so the next apply runs them again
+pub fn applied_state(
+    config: &Config,
+    old: &Config,
+    failed: &[Change],
+    left_out: &[Change],
+) -> Result<Config, DottyError> {
+    let mut state = config.clone();
+    state.record_hashes()?;
+    state.record_config_hash(config)?;
+    state.record_hook_runs(old, &[]);
+    for change in left_out {
+        state.revert(change, old);
+    }
+    for change in failed {
+        state.record_failure(change, old);
+    }
+    state.keep_disabled(old);
+    Ok(state)
+}
+
+/// What `apply` did
+#[derive(Debug)]
+pub struct Applied {
+    /// Every change that was run, including the failed ones
+    pub changes: Vec<Change>,
+    pub failed: Vec<Change>,
+    /// Changes left for the next apply since one of their actions was declined
+    pub declined: Vec<Change>,
+    /// The state to write for the next apply
+    pub state: Config,
+}
+
+/// Brings the system from `state` to `config` without writing the new state, pass `&mut ()` as
+/// the reporter to confirm everything silently
+pub fn apply(
+    config: &Config,
+    state: &Config,
+    options: ExecuteOptions,
+    reporter: &mut impl Reporter,
+) -> Result<Applied, DottyError> {
+    let changes = config.diff(state.clone())?;
+    let Executed { failed, declined } = execute(changes.clone(), config, options, reporter)?;
+    let state = applied_state(config, state, &failed, &declined)?;
+    let declined_ids = declined.iter().map(Change::id).collect::<Vec<_>>();
+    let changes = changes
+        .into_iter()
+        .filter(|change| !declined_ids.contains(&change.id()))
+        .collect();
+    Ok(Applied {
+        changes,
+        failed,
+        declined,
+        state,
+    })
+}
+
+/// Runs the update commands of every manager and hook, leaving out the hooks `state` says ran
+/// less than their `min_interval` ago
+pub fn update(
+    config: &Config,
+    state: &Config,
+    options: ExecuteOptions,
+    reporter: &mut impl Reporter,
+) -> Result<Executed, DottyError> {
+    execute(config.update(state)?, config, options, reporter)
+}
+
+/// The state to write after an update that ran `changes`, recording when the throttled update
+/// hooks among them last ran
+pub fn updated_state(
+    config: &Config,
+    old: &Config,
+    changes: &[Change],
+    failed: &[Change],
+) -> Result<Config, DottyError> {
+    let failed = failed.iter().map(Change::id).collect::<Vec<_>>();
+    let succeeded = changes
+        .iter()
+        .filter(|change| !failed.contains(&change.id()))
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut state = config.clone();
+    state.record_config_hash(config)?;
+    state.record_hook_runs(old, &succeeded);
+    state.keep_disabled(old);
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Declines every action it is asked about
+    struct Decline;
+
+    impl Reporter for Decline {
+        fn confirm(&mut self, _action: &Action) -> Result<bool> {
+            Ok(false)
+        }
+    }
+
+    #[test]
+    fn declined_changes_are_left_for_the_next_apply() {
+        let manager = "[managers.m]\nadd = \"true #:?\"\nremove = \"false #:?\"\n";
+        let config: Config = toml::from_str(manager).unwrap();
+        let state: Config =
+            toml::from_str(&format!("{manager}\n[packages]\nm = [\"a\"]\n")).unwrap();
+
+        let applied = apply(&config, &state, ExecuteOptions::default(), &mut Decline).unwrap();
+        assert!(applied.changes.is_empty());
+        assert!(applied.failed.is_empty());
+        assert_eq!(applied.declined.len(), 1);
+        assert!(applied.state.has_package("m", "a"));
+        assert!(matches!(
+            config.diff(applied.state).unwrap()[..],
+            [Change::RemovePackage { .. }]
+        ));
+    }
+}
@@ -1,16 +1,31 @@
-#![feature(exit_status_error)]
-
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::{IsTerminal, Write};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, Result};
+use clap::{CommandFactory, Parser, Subcommand};
 use colored::Colorize;
-use config::Config;
+use dotty::journal::Journal;
+use dotty::resume::Progress;
+use dotty::verbosity::redact;
+use dotty::{
+    check_config, config, history, pull_modules, read_config, state, textdiff, write_config,
+    Action, Change, Config, DottyError, ExecuteOptions, Executed, Level, Package, PackageQuery,
+    Preset, Reporter, Skip,
+};
 
-mod config;
+mod completions;
+mod watch;
 
 const ROOT_FILE_NAME: &str = "dotty.toml";
 const DEFAULT_STATE_FILE_NAME: &str = "dotty.state.toml";
+const RESUME_FILE_NAME: &str = "dotty.resume.toml";
+const JOURNAL_FILE_NAME: &str = "dotty.journal.toml";
 
 /// Dotty - A CLI based dotfile and package manager
 #[derive(Parser, Debug)]
@@ -23,23 +38,194 @@ struct CliCommand {
     #[arg(short, long)]
     state: Option<PathBuf>,
 
+    /// List everything that was not changed and why
+    #[arg(long, global = true)]
+    explain_skip: bool,
+
+    /// Assume yes instead of asking before destructive actions
+    #[arg(short = 'y', long, global = true)]
+    yes: bool,
+
+    /// Print more details, can be repeated
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only print errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Keep applying the remaining changes when one fails
+    #[arg(short, long, global = true)]
+    keep_going: bool,
+
+    /// How many changes to run at once, defaults to the number of CPUs
+    #[arg(short, long, global = true)]
+    jobs: Option<NonZeroUsize>,
+
+    /// Times failing package installs and updates are retried, for managers without `retries`
+    #[arg(long, global = true, default_value_t = 0)]
+    retries: u32,
+
+    /// Only run some changes: `files`, `packages`, `hooks`, `manager:<name>`, `hook:<name>` or
+    /// `file:<target>`, can be repeated
+    #[arg(long, global = true, visible_alias = "filter")]
+    only: Vec<Selector>,
+
+    /// Run everything but some changes, takes the same values as `--only` and can be repeated
+    #[arg(long, global = true)]
+    except: Vec<Selector>,
+
+    /// Skip hooks and scripts, they run on the next apply without it
+    #[arg(long, global = true)]
+    no_hooks: bool,
+
+    /// Profiles to use on top of the base config, can be repeated
+    #[arg(short, long, global = true)]
+    profile: Vec<Box<str>>,
+
+    /// When to color the output
+    #[arg(long, global = true, value_enum, default_value_t)]
+    color: ColorChoice,
+
     #[command(subcommand)]
     command: Command,
 }
 
+/// A kind of change selected with `--only` or `--except`
+#[derive(Debug, Clone)]
+enum Selector {
+    Files,
+    Packages,
+    Hooks,
+    Manager(Box<str>),
+    Hook(Box<str>),
+    File(PathBuf),
+}
+
+impl FromStr for Selector {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "files" => Ok(Self::Files),
+            "packages" => Ok(Self::Packages),
+            "hooks" => Ok(Self::Hooks),
+            _ => match value.split_once(':') {
+                Some(("manager", name)) if !name.is_empty() => Ok(Self::Manager(name.into())),
+                Some(("hook", name)) if !name.is_empty() => Ok(Self::Hook(name.into())),
+                Some(("file", target)) if !target.is_empty() => {
+                    let target = shellexpand::tilde(target);
+                    Ok(Self::File(std::path::absolute(target.as_ref())?))
+                }
+                _ => Err(anyhow!(
+                    "expected `files`, `packages`, `hooks`, `manager:<name>`, `hook:<name>` or `file:<target>`"
+                )),
+            },
+        }
+    }
+}
+
+impl Selector {
+    /// Manager update commands count as package changes of their manager
+    fn matches(&self, change: &Change, config: &Config) -> bool {
+        let manager = change
+            .manager()
+            .or_else(|| change.node().filter(|node| config.is_manager(node)));
+        match self {
+            Self::Files => matches!(change, Change::CopyFile(..) | Change::RemoveFile(..)),
+            Self::Packages => manager.is_some(),
+            Self::Hooks => matches!(change, Change::RawCommand { .. }) && manager.is_none(),
+            Self::Manager(name) => manager == Some(name),
+            Self::Hook(name) => {
+                Self::Hooks.matches(change, config) && change.node() == Some(name.as_ref())
+            }
+            Self::File(target) => match change {
+                Change::CopyFile(_, path) | Change::RemoveFile(_, path) => path == target,
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Expands `~` and environment variables in a path given on the command line, since quoting it
+/// stops the shell from doing so
+fn expand_path(path: &Path) -> Result<PathBuf> {
+    let Some(text) = path.to_str() else {
+        return Ok(path.into());
+    };
+    let expanded =
+        shellexpand::full(text).map_err(|err| anyhow!("Could not expand {text}: {err}"))?;
+    Ok(PathBuf::from(expanded.as_ref()))
+}
+
 impl CliCommand {
-    fn config_path(&self) -> PathBuf {
-        self.config
-            .as_ref()
-            .map(PathBuf::from)
-            .unwrap_or_else(|| PathBuf::from(ROOT_FILE_NAME))
+    fn config_path(&self) -> Result<PathBuf> {
+        match &self.config {
+            Some(config) => expand_path(config),
+            None => discover_config(),
+        }
+    }
+
+    /// Defaults to living next to a config found in the current directory or its parents.
+    /// Otherwise the one in the current directory is used if there is one, and
+    /// `$XDG_STATE_HOME/dotty` if not
+    fn state_path(&self) -> Result<PathBuf> {
+        if let Some(state) = &self.state {
+            return expand_path(state);
+        }
+        // An explicit config keeps its state in the current directory, as it always has
+        if self.config.is_some() {
+            return Ok(PathBuf::from(DEFAULT_STATE_FILE_NAME));
+        }
+        if let Some(config) = find_config_upwards()? {
+            return Ok(config.with_file_name(DEFAULT_STATE_FILE_NAME));
+        }
+        discover_config()?;
+
+        let current = PathBuf::from(DEFAULT_STATE_FILE_NAME);
+        if current.exists() {
+            return Ok(current);
+        }
+        Ok(dirs::state_dir()
+            .map(|dir| dir.join("dotty").join(DEFAULT_STATE_FILE_NAME))
+            .unwrap_or(current))
+    }
+
+    fn resume_path(&self) -> Result<PathBuf> {
+        Ok(self.state_path()?.with_file_name(RESUME_FILE_NAME))
+    }
+
+    fn journal_path(&self) -> Result<PathBuf> {
+        Ok(self.state_path()?.with_file_name(JOURNAL_FILE_NAME))
+    }
+
+    fn level(&self) -> Level {
+        Level::from_flags(self.verbose, self.quiet)
     }
 
-    fn state_path(&self) -> PathBuf {
-        self.state
-            .as_ref()
-            .map(PathBuf::from)
-            .unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_FILE_NAME))
+    /// Splits off the changes not picked by `--only` or left out by `--except` or `--no-hooks`
+    fn select(&self, changes: Vec<Change>, config: &Config) -> (Vec<Change>, Vec<Change>) {
+        if self.only.is_empty() && self.except.is_empty() && !self.no_hooks {
+            return (changes, Vec::new());
+        }
+        changes.into_iter().partition(|change| {
+            let picked = self.only.is_empty()
+                || self
+                    .only
+                    .iter()
+                    .any(|selector| selector.matches(change, config));
+            let excepted = self
+                .except
+                .iter()
+                .any(|selector| selector.matches(change, config));
+            picked && !excepted && !(self.no_hooks && Selector::Hooks.matches(change, config))
+        })
+    }
+
+    fn jobs(&self) -> usize {
+        self.jobs
+            .or_else(|| std::thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get)
     }
 }
 
@@ -52,11 +238,90 @@ enum Command {
     Init {
         /// Path to create config at
         path: Option<PathBuf>,
+        /// Package manager to set up, detected from the installed ones by default
+        #[arg(long, value_enum)]
+        preset: Option<Preset>,
     },
     /// Apply migrations
-    Apply,
+    Apply {
+        /// Skip changes completed by a previous interrupted apply
+        #[arg(long)]
+        resume: bool,
+        /// Remove files whose entries were dropped from the config
+        #[arg(long)]
+        prune: bool,
+        /// Deploy every file again, even the ones that look up to date
+        #[arg(long)]
+        force: bool,
+    },
+    /// Apply as if the state were empty, installing every package and running every once hook
+    Bootstrap,
+    /// Apply again every time the config, its modules or file sources change
+    Watch,
     /// Update stuff
     Update,
+    /// Show every change an apply would make in the order it would make them, with the actions
+    /// and commands each one runs, without running anything
+    Plan {
+        /// Include removing files whose entries were dropped from the config
+        #[arg(long)]
+        prune: bool,
+        /// Deploy every file again, even the ones that look up to date
+        #[arg(long)]
+        force: bool,
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    /// Summarize pending changes, exiting with a non-zero status if there are any
+    Status {
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    /// Check the config and its imports for problems without changing anything
+    Validate,
+    /// Check the host can apply the config, like whether the programs it runs are installed
+    Doctor,
+    /// Show recent applies and updates from the history file
+    Log {
+        /// How many runs to show
+        #[arg(short = 'n', long, default_value_t = 10)]
+        count: usize,
+    },
+    /// Show how applying would change the contents of a file
+    DiffFile {
+        /// Target path of the file
+        target: PathBuf,
+    },
+    /// Trace why applying would or would not copy a file
+    Explain {
+        /// Target path of the file
+        target: PathBuf,
+    },
+    /// Print a shell completion script to stdout
+    Completions {
+        /// Shell to generate the script for
+        shell: completions::Shell,
+    },
+    /// List the packages of each manager, or the ones an apply would install or remove
+    Packages {
+        #[arg(value_enum)]
+        query: PackageQuery,
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    /// Undo the last apply that changed anything: restore the backups of the files it wrote,
+    /// remove the ones it created and put the state back
+    Rollback,
+    /// Update the state to match the packages the managers report as installed
+    Reconcile,
+    /// Uninstall packages through their manager and forget them in the state
+    Remove {
+        /// Manager the packages belong to
+        manager: String,
+        /// Packages to uninstall
+        #[arg(required = true)]
+        packages: Vec<String>,
+    },
 }
 
 #[expect(
@@ -70,112 +335,900 @@ enum DebugCommand {
     /// Prints the current state
     PrintState,
     /// Prints the difference between the current state and the config
-    PrintDiff,
+    PrintDiff {
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
     /// Prints the actions to be performed
-    PrintActions,
+    PrintActions {
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
 }
 
-fn main() -> Result<()> {
+/// How commands listing changes print them
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// Colored text meant for people
+    #[default]
+    Human,
+    /// JSON for scripts and editors
+    Json,
+}
+
+/// When the output is colored
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum ColorChoice {
+    /// Color when printing to a terminal, unless `NO_COLOR` is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Prints errors the same way as returning them from `main`, but with secrets redacted
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", redact(&format!("{err:?}")));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<()> {
     let cli = CliCommand::parse();
+    match cli.color {
+        ColorChoice::Auto => {}
+        ColorChoice::Always => colored::control::set_override(true),
+        ColorChoice::Never => colored::control::set_override(false),
+    }
+    let level = cli.level();
+    if cli.jobs.is_some()
+        && !matches!(
+            cli.command,
+            Command::Apply { .. } | Command::Bootstrap | Command::Watch | Command::Update
+        )
+    {
+        eprintln!(
+            "{}",
+            "Warning: --jobs has no effect, this command does not run changes in parallel".yellow()
+        );
+    }
     match cli.command.clone() {
         Command::Debug(debug) => do_debug(cli, debug)?,
-        Command::Init { path } => {
+        Command::Init { path, preset } => {
             let path = path.unwrap_or_else(|| PathBuf::from(ROOT_FILE_NAME));
-            create_default_config(&path)?;
+            create_default_config(&path, preset, level)?;
+        }
+        Command::Apply {
+            resume,
+            prune,
+            force,
+        } => {
+            let config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            let _lock = state::lock(&cli.state_path()?)?;
+            let state = state::read(&cli.state_path()?, level).unwrap_or_default();
+            apply(&cli, &config, state, resume, prune, force)?;
+        }
+        Command::Bootstrap => {
+            let config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            let _lock = state::lock(&cli.state_path()?)?;
+            apply(&cli, &config, Config::default(), false, false, false)?;
+        }
+        Command::Watch => watch(&cli)?,
+        Command::Update => {
+            let _lock = state::lock(&cli.state_path()?)?;
+            let mut config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            if !config.git_modules().is_empty() {
+                pull_modules(&config, level)?;
+                config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            }
+
+            let state = state::read(&cli.state_path()?, level).unwrap_or_default();
+            let (changes, skips) = config.update_explained(&state)?;
+            if cli.explain_skip {
+                print_skips(&skips, level);
+            }
+            let (changes, _) = cli.select(changes, &config);
+            let rendered = render_changes(&changes);
+            let Executed { failed, declined } =
+                execute_changes(&cli, changes.clone(), &config, None, |_| Ok(()))?;
+            let changes = without(changes, &declined);
+            let new_state = dotty::updated_state(&config, &state, &changes, &failed)?;
+            state::write(&cli.state_path()?, &new_state, level)?;
+            if failed.is_empty() {
+                record_history(&config, "update", rendered)?;
+            }
+            report_failed(&failed)?;
+        }
+        Command::Plan {
+            prune,
+            force,
+            format,
+        } => {
+            let config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            let state = state::read(&cli.state_path()?, level).unwrap_or_default();
+            let (diff, skips) = config.diff_explained(state, force, level)?;
+            if cli.explain_skip {
+                print_skips(&skips, level);
+            }
+            let (diff, _) = cli.select(diff, &config);
+            let diff = diff
+                .into_iter()
+                .filter(|change| prune || !matches!(change, Change::RemoveFile(..)))
+                .collect();
+            let plan = dotty::plan(diff, &config)?;
+            match format {
+                Format::Human => print_plan(&plan),
+                Format::Json => println!("{}", serde_json::to_string_pretty(&plan)?),
+            }
         }
-        Command::Apply => {
-            let config = read_config(&cli.config_path())?;
-            let state = read_config(&cli.state_path()).unwrap_or_default();
+        Command::Status { format } => {
+            let config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            let state = state::read(&cli.state_path()?, level).unwrap_or_default();
 
-            let diff = config.diff(state)?;
-            for change in diff {
-                println!("[*] {}", change.render());
-                let actions = change.action(&config)?;
-                for action in actions {
-                    println!("[>] {}", action.render());
-                    action.execute()?
+            let diff = match format {
+                Format::Human => {
+                    notice_stale_state(&config, &state, level)?;
+                    let diff = config.diff(state)?;
+                    print_status(&diff, level);
+                    diff
+                }
+                Format::Json => {
+                    let diff = config.diff(state)?;
+                    println!("{}", serde_json::to_string_pretty(&diff)?);
+                    diff
+                }
+            };
+            if !diff.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Command::Completions { shell } => {
+            print!("{}", completions::generate(shell, CliCommand::command()));
+        }
+        Command::Log { count } => {
+            let config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            let path = config
+                .history_file()
+                .ok_or(anyhow!("No history_file set under [dotty]"))?;
+            for entry in history::read(&path, count)? {
+                println!("{} {}", entry.time().blue(), entry.command.bold());
+                for change in entry.changes.iter() {
+                    println!("  {change}");
                 }
             }
+        }
+        Command::Validate => {
+            let problems = check_config(&cli.config_path()?, &cli.profile, level);
+            if problems.is_empty() {
+                level.log(Level::Normal, "No problems found".green());
+            } else {
+                for problem in problems.iter() {
+                    eprintln!("[!] {}", problem.red());
+                }
+                eprintln!("{} problem(s) found", problems.len());
+                std::process::exit(1);
+            }
+        }
+        Command::Doctor => {
+            let config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            let diagnoses = config.diagnose();
+            let failed = diagnoses
+                .iter()
+                .filter(|diagnosis| diagnosis.result.is_err())
+                .count();
+            for diagnosis in diagnoses {
+                match diagnosis.result {
+                    Ok(()) => level.log(Level::Normal, format!("[+] {}", diagnosis.check).green()),
+                    Err(err) => {
+                        eprintln!(
+                            "[!] {}: {}",
+                            diagnosis.check,
+                            redact(&format!("{err:#}")).red()
+                        )
+                    }
+                }
+            }
+            if failed > 0 {
+                eprintln!("{failed} check(s) failed");
+                std::process::exit(1);
+            }
+        }
+        Command::DiffFile { target } => {
+            let config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            print_file_diff(&config, &target)?;
+        }
+        Command::Explain { target } => {
+            let config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            let state = state::read(&cli.state_path()?, level).unwrap_or_default();
+            for line in config.explain_file(&state, &target)? {
+                println!("{line}");
+            }
+        }
+        Command::Packages { query, format } => {
+            let config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            let state = state::read(&cli.state_path()?, level).unwrap_or_default();
+            let packages = config.query_packages(&state, query);
+            match format {
+                Format::Human => {
+                    for (manager, packages) in packages {
+                        println!("{}", format!("{manager}:").bold());
+                        for package in packages {
+                            println!("  {package}");
+                        }
+                    }
+                }
+                Format::Json => println!("{}", serde_json::to_string_pretty(&packages)?),
+            }
+        }
+        Command::Rollback => {
+            let state_path = cli.state_path()?;
+            let _lock = state::lock(&state_path)?;
+            let journal_path = cli.journal_path()?;
+            let journal = Journal::read(&journal_path)?;
 
-            write_config(&cli.state_path(), &config)?;
+            let lost = journal.restore_files(level)?;
+            match &journal.state {
+                Some(state) => std::fs::write(&state_path, state)?,
+                None if state_path.exists() => std::fs::remove_file(&state_path)?,
+                None => {}
+            }
+            level.log(
+                Level::Normal,
+                "Restored the state from before the last apply".green(),
+            );
+            for target in lost {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "Warning: {} was overwritten without a backup, set backup_dir to be able to roll it back",
+                        target.display()
+                    )
+                    .yellow()
+                );
+            }
+            if !journal.packages.is_empty() {
+                println!(
+                    "Packages are not rolled back, remove the ones the apply installed yourself:"
+                );
+                for (manager, packages) in journal.packages.iter() {
+                    let packages = packages.iter().map(ToString::to_string).collect::<Vec<_>>();
+                    println!("  {manager}: {}", packages.join(", "));
+                }
+            }
+            Progress::remove(&cli.resume_path()?)?;
+            Journal::remove(&journal_path)?;
         }
-        Command::Update => {
-            let config = read_config(&cli.config_path())?;
-
-            let changes = config.update()?;
-            for change in changes {
-                println!("[*] {}", change.render());
-                let actions = change.action(&config)?;
-                for action in actions {
-                    println!("[>] {}", action.render());
-                    action.execute()?
+        Command::Reconcile => {
+            let config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            let _lock = state::lock(&cli.state_path()?)?;
+            let mut state = state::read(&cli.state_path()?, level).unwrap_or_default();
+
+            let report = state.reconcile(&config)?;
+            if report.is_empty() {
+                level.log(
+                    Level::Normal,
+                    "State matches the installed packages".green(),
+                );
+            }
+            for config::Reconciled {
+                manager,
+                added,
+                removed,
+            } in report
+            {
+                for package in added {
+                    level.log(
+                        Level::Normal,
+                        format!(
+                            "{manager}: {}",
+                            format!("+{package} (already installed)").green()
+                        ),
+                    );
+                }
+                for package in removed {
+                    level.log(
+                        Level::Normal,
+                        format!("{manager}: {}", format!("-{package} (not installed)").red()),
+                    );
+                }
+            }
+            state::write(&cli.state_path()?, &state, level)?;
+        }
+        Command::Remove { manager, packages } => {
+            let config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            let _lock = state::lock(&cli.state_path()?)?;
+            let mut state = state::read(&cli.state_path()?, level).unwrap_or_default();
+
+            let packages = packages
+                .into_iter()
+                .map(|package| Package::try_from(Box::from(package)))
+                .collect::<Result<Vec<_>>>()?;
+            let change = config.remove_change(&manager, packages.clone())?;
+            let Executed { failed, declined } =
+                execute_changes(&cli, vec![change], &config, None, |_| Ok(()))?;
+            report_failed(&failed)?;
+            if !declined.is_empty() {
+                return Ok(());
+            }
+
+            state.forget_packages(&manager, &packages);
+            state::write(&cli.state_path()?, &state, level)?;
+
+            for package in packages {
+                if config.has_package(&manager, &package.name) {
+                    level.log(
+                        Level::Normal,
+                        format!(
+                            "{} is still in the config and will be reinstalled on the next apply",
+                            package.name
+                        )
+                        .yellow(),
+                    );
                 }
             }
-            write_config(&cli.state_path(), &config)?;
         }
     }
 
     Ok(())
 }
 
+/// Applies `config` over `state` and writes the new state, the body of `dotty apply`
+fn apply(
+    cli: &CliCommand,
+    config: &Config,
+    state: Config,
+    resume: bool,
+    prune: bool,
+    force: bool,
+) -> Result<()> {
+    let level = cli.level();
+
+    let resume_path = cli.resume_path()?;
+    let mut progress = if resume {
+        Progress::read(&resume_path, level)?
+    } else {
+        Progress::default()
+    };
+
+    notice_stale_state(config, &state, level)?;
+    let (diff, skips) = config.diff_explained(state.clone(), force, level)?;
+    if cli.explain_skip {
+        print_skips(&skips, level);
+    }
+    let (diff, mut unselected) = cli.select(diff, config);
+    let (diff, dropped): (Vec<_>, Vec<_>) = diff
+        .into_iter()
+        .partition(|change| prune || !matches!(change, Change::RemoveFile(..)));
+    if !dropped.is_empty() {
+        eprintln!(
+            "{}",
+            format!(
+                "Warning: {} file(s) dropped from the config were left in place, apply with --prune to remove them",
+                dropped.len()
+            )
+            .yellow()
+        );
+    }
+    unselected.extend(dropped);
+    let mut pending = Vec::with_capacity(diff.len());
+    for change in diff {
+        if progress.is_completed(&change.id()) {
+            level.log(Level::Normal, format!("[~] {}", change.render()));
+        } else {
+            pending.push(change);
+        }
+    }
+
+    let rendered = render_changes(&pending);
+    let started = Instant::now();
+    let journal = Mutex::new(Journal::start(&cli.state_path()?, &pending));
+    let Executed { failed, declined } =
+        execute_changes(cli, pending.clone(), config, Some(&journal), |id| {
+            progress.complete(id);
+            progress.write(&resume_path)
+        })?;
+    let pending = without(pending, &declined);
+    print_summary(&pending, &failed, started.elapsed(), level);
+
+    // Failed, declined and unselected changes are left out of the state so the next apply
+    // runs them
+    unselected.extend(declined);
+    let new_state = dotty::applied_state(config, &state, &failed, &unselected)?;
+    state::write(&cli.state_path()?, &new_state, level)?;
+    // Applies that changed nothing keep the journal of the last one that did
+    if !pending.is_empty() {
+        let mut journal = journal.into_inner().unwrap();
+        journal.finish(&pending, &failed);
+        journal.write(&cli.journal_path()?)?;
+    }
+    if failed.iter().all(Change::is_optional) {
+        Progress::remove(&resume_path)?;
+        record_history(config, "apply", rendered)?;
+    }
+    report_failed(&failed)
+}
+
+/// Applies the config every time it, its modules or the sources of its files change
+fn watch(cli: &CliCommand) -> Result<()> {
+    let level = cli.level();
+    let config_path = cli.config_path()?;
+    let mut paths = vec![config_path.clone()];
+    loop {
+        // Reloaded every time, since the change may have added or removed imports
+        let result = read_config(&config_path, &cli.profile, level)
+            .map_err(anyhow::Error::from)
+            .and_then(|config| {
+                paths = config.watched_paths();
+                let _lock = state::lock(&cli.state_path()?)?;
+                let state = state::read(&cli.state_path()?, level).unwrap_or_default();
+                apply(cli, &config, state, false, false, false)
+            });
+        if let Err(err) = result {
+            eprintln!("{}", redact(&format!("Error: {err:#}")).red());
+        }
+
+        level.log(
+            Level::Normal,
+            format!(
+                "Watching {} path(s) for changes, press Ctrl-C to stop",
+                paths.len()
+            )
+            .dimmed(),
+        );
+        watch::wait_for_change(&paths);
+    }
+}
+
+/// Runs the changes with the CLI's options, printing every failure as it happens.
+/// Backups taken are recorded in `journal` when given
+fn execute_changes(
+    cli: &CliCommand,
+    changes: Vec<Change>,
+    config: &Config,
+    journal: Option<&Mutex<Journal>>,
+    on_complete: impl FnMut(Box<str>) -> Result<()> + Sync,
+) -> Result<Executed> {
+    let options = ExecuteOptions {
+        jobs: cli.jobs(),
+        keep_going: cli.keep_going,
+        retries: cli.retries,
+        level: cli.level(),
+    };
+    let mut console = Console {
+        level: cli.level(),
+        yes: cli.yes,
+        journal,
+        on_complete,
+    };
+    Ok(dotty::execute(changes, config, options, &mut console)?)
+}
+
+/// `changes` without the ones in `left_out`
+fn without(changes: Vec<Change>, left_out: &[Change]) -> Vec<Change> {
+    let left_out = left_out.iter().map(Change::id).collect::<HashSet<_>>();
+    changes
+        .into_iter()
+        .filter(|change| !left_out.contains(&change.id()))
+        .collect()
+}
+
+/// Prints progress as changes run, asking before destructive actions unless `yes` is set
+struct Console<'a, F> {
+    level: Level,
+    yes: bool,
+    journal: Option<&'a Mutex<Journal>>,
+    /// Called with the id of every change that succeeded
+    on_complete: F,
+}
+
+impl<F: FnMut(Box<str>) -> Result<()> + Sync> Reporter for Console<'_, F> {
+    fn confirm(&mut self, action: &Action) -> Result<bool> {
+        if self.yes {
+            return Ok(true);
+        }
+
+        println!("{}", redact(&format!("[?] {}", action.render())));
+        let confirmed = confirm()?;
+        if !confirmed {
+            self.level
+                .log(Level::Normal, format!("[-] {}", "Skipped".dimmed()));
+        }
+        Ok(confirmed)
+    }
+
+    fn change_started(&self, change: &Change) {
+        self.level
+            .log(Level::Normal, format!("[*] {}", change.render()));
+    }
+
+    fn action_started(&self, action: &Action) {
+        self.level
+            .log(Level::Normal, format!("[>] {}", action.render()));
+    }
+
+    fn action_finished(&self, action: &Action, success: bool) {
+        if let (Some(journal), Action::Backup { target, backup, .. }, true) =
+            (self.journal, action, success)
+        {
+            journal.lock().unwrap().record_backup(target, backup);
+        }
+    }
+
+    fn change_finished(&mut self, change: &Change, result: &Result<(), DottyError>) -> Result<()> {
+        match result {
+            Ok(()) => (self.on_complete)(change.id()),
+            Err(err) if change.is_optional() => {
+                let warning = format!(
+                    "Warning: optional {} failed to install, skipping it: {err:#}",
+                    change.render().clear()
+                );
+                eprintln!("{}", redact(&warning).yellow());
+                Ok(())
+            }
+            Err(err) => {
+                let failure = format!("[!] {}: {}", change.render(), format!("{err:#}").red());
+                eprintln!("{}", redact(&failure));
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Renders the changes without colors, for the history file
+fn render_changes(changes: &[Change]) -> Vec<Box<str>> {
+    changes
+        .iter()
+        .map(|change| change.render().clear().to_string().into())
+        .collect()
+}
+
+fn record_history(config: &Config, command: &str, changes: Vec<Box<str>>) -> Result<()> {
+    match config.history_file() {
+        Some(path) => history::append(&path, &history::Entry::now(command, changes)),
+        None => Ok(()),
+    }
+}
+
+/// Prints the changes that failed with `--keep-going`, erroring if there were any. Optional
+/// packages were already warned about and are left out
+fn report_failed(failed: &[Change]) -> Result<()> {
+    let failed = failed
+        .iter()
+        .filter(|change| !change.is_optional())
+        .collect::<Vec<_>>();
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!("{}", "Failed changes:".red());
+    for change in &failed {
+        eprintln!("{}", redact(&format!("[!] {}", change.render())));
+    }
+    Err(anyhow!("{} change(s) failed", failed.len()))
+}
+
+/// Asks the user to confirm an action, a non interactive stdin is treated as a no
+fn confirm() -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        println!(
+            "[?] {}",
+            "Not a terminal, assuming no (use -y to assume yes)".yellow()
+        );
+        return Ok(false);
+    }
+
+    print!("[?] Proceed? [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
 fn do_debug(cli: CliCommand, debug: DebugCommand) -> Result<(), anyhow::Error> {
+    let level = cli.level();
     match debug {
         DebugCommand::PrintConfig => {
-            let config = read_config(&cli.config_path())?;
+            let config = read_config(&cli.config_path()?, &cli.profile, level)?;
             dbg!(config);
         }
         DebugCommand::PrintState => {
-            let state = read_config(&cli.state_path()).unwrap_or_default();
+            let state = state::read(&cli.state_path()?, level).unwrap_or_default();
             dbg!(state);
         }
-        DebugCommand::PrintDiff => {
-            let config = read_config(&cli.config_path())?;
-            let state = read_config(&cli.state_path()).unwrap_or_default();
-            let diff = config.diff(state)?;
+        DebugCommand::PrintDiff { format } => {
+            let config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            let state = state::read(&cli.state_path()?, level).unwrap_or_default();
+            let (diff, skips) = config.diff_explained(state, false, level)?;
+            if format == Format::Json {
+                let mut entries = diff
+                    .iter()
+                    .map(|change| {
+                        serde_json::json!({
+                            "priority": change.priority(&config),
+                            "change": change,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                // Skipped entries have no change, so they are listed with their reason instead
+                if cli.explain_skip {
+                    entries.extend(skips.iter().map(|skip| {
+                        serde_json::json!({
+                            "skipped": skip.entry,
+                            "reason": skip.reason.describe(),
+                        })
+                    }));
+                }
+                println!("{}", serde_json::to_string_pretty(&entries)?);
+                return Ok(());
+            }
+
+            if cli.explain_skip {
+                print_skips(&skips, level);
+            }
             for change in diff {
                 println!("[{}] {}", change.priority(&config), change.render());
             }
         }
-        DebugCommand::PrintActions => {
-            let config = read_config(&cli.config_path())?;
-            let state = read_config(&cli.state_path()).unwrap_or_default();
+        DebugCommand::PrintActions { format } => {
+            let config = read_config(&cli.config_path()?, &cli.profile, level)?;
+            let state = state::read(&cli.state_path()?, level).unwrap_or_default();
             let diff = config.diff(state)?;
+            let mut actions = Vec::new();
             for change in diff {
-                let actions = change.action(&config)?;
-                for action in actions {
-                    println!("{}", action.render());
+                actions.extend(change.action(&config)?);
+            }
+            match format {
+                Format::Human => {
+                    for action in actions {
+                        println!("{}", action.render());
+                    }
                 }
+                Format::Json => println!("{}", serde_json::to_string_pretty(&actions)?),
             }
         }
     }
     Ok(())
 }
 
-fn read_config(path: &Path) -> Result<Config> {
-    println!("Reading config at {}", path.to_string_lossy().blue());
+fn print_plan(plan: &[dotty::Step]) {
+    if plan.is_empty() {
+        println!("{}", "Nothing to do".green());
+    }
+    for step in plan {
+        let parallel = match step.changes.len() {
+            1 => String::new(),
+            count => format!(", {count} changes in parallel"),
+        };
+        println!("{}", format!("Priority {}{parallel}", step.priority).bold());
+        for planned in step.changes.iter() {
+            println!("  [*] {}", planned.change.render());
+            for action in planned.actions.iter() {
+                println!("      [>] {}", action.render());
+            }
+        }
+    }
+}
+
+/// Counts what an apply did, `failed` being the subset of `changes` that did not succeed
+fn print_summary(changes: &[Change], failed: &[Change], elapsed: Duration, level: Level) {
+    let failed_ids = failed.iter().map(Change::id).collect::<HashSet<_>>();
+    let mut managers = BTreeSet::new();
+    let (mut added, mut removed, mut files, mut hooks) = (0, 0, 0, 0);
+    for change in changes {
+        if failed_ids.contains(&change.id()) {
+            continue;
+        }
+        match change {
+            Change::AddPackage { manager, packages } => {
+                added += packages.len();
+                managers.insert(manager.as_ref());
+            }
+            Change::RemovePackage { manager, packages } => {
+                removed += packages.len();
+                managers.insert(manager.as_ref());
+            }
+            Change::CopyFile(..) | Change::RemoveFile(..) => files += 1,
+            Change::RawCommand { .. } => hooks += 1,
+        }
+    }
 
-    let content = std::fs::read_to_string(path)?;
-    let mut config: Config = toml::from_str(&content)?;
-    let directory = path.parent().unwrap_or(Path::new("."));
-    config.load_dependencies(directory)?;
-    Ok(config)
+    let mut summary = format!(
+        "{} added, {} removed",
+        format!("{added} package(s)").green(),
+        format!("{removed} package(s)").red()
+    );
+    if !managers.is_empty() {
+        let managers = managers.into_iter().collect::<Vec<_>>().join(", ");
+        summary.push_str(&format!(" ({managers})"));
+    }
+    summary.push_str(&format!(
+        ", {} updated, {} run in {:.1}s",
+        format!("{files} file(s)").purple(),
+        format!("{hooks} command(s)").cyan(),
+        elapsed.as_secs_f64()
+    ));
+    if !failed.is_empty() {
+        summary.push_str(&format!(", {}", format!("{} failed", failed.len()).red()));
+    }
+    level.log(Level::Normal, summary);
 }
 
-fn write_config(path: &Path, config: &Config) -> Result<()> {
-    println!("Writing config at {}", path.to_string_lossy().blue());
+fn print_status(diff: &[Change], level: Level) {
+    if diff.is_empty() {
+        level.log(Level::Normal, "Up to date".green());
+        return;
+    }
 
-    let content = toml::to_string(config)?;
-    std::fs::write(path, content)?;
+    let mut packages: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    let mut files = 0;
+    let mut dropped = 0;
+    let mut hooks = Vec::new();
+    for change in diff {
+        match change {
+            Change::AddPackage {
+                manager,
+                packages: added,
+            } => {
+                packages.entry(manager).or_default().0 += added.len();
+            }
+            Change::RemovePackage {
+                manager,
+                packages: removed,
+            } => {
+                packages.entry(manager).or_default().1 += removed.len();
+            }
+            Change::CopyFile(..) => files += 1,
+            Change::RemoveFile(..) => dropped += 1,
+            Change::RawCommand { .. } => hooks.push(change.render().clear()),
+        }
+    }
 
+    for (manager, (added, removed)) in packages {
+        level.log(
+            Level::Normal,
+            format!(
+                "{manager}: {} {}",
+                format!("+{added}").green(),
+                format!("-{removed}").red()
+            ),
+        );
+    }
+    if files > 0 {
+        level.log(Level::Normal, format!("files: {files} to update").purple());
+    }
+    if dropped > 0 {
+        level.log(
+            Level::Normal,
+            format!("files: {dropped} dropped from the config").red(),
+        );
+    }
+    for hook in hooks {
+        level.log(Level::Normal, format!("hook: {hook}").cyan());
+    }
+}
+
+/// Points out that the config changed since the state was written, which explains a large diff
+fn notice_stale_state(config: &Config, state: &Config, level: Level) -> Result<()> {
+    if state.is_stale_for(config)? {
+        level.log(
+            Level::Normal,
+            "Note: the config changed since the state was last written".blue(),
+        );
+    }
     Ok(())
 }
 
-fn create_default_config(path: &Path) -> Result<()> {
-    println!("Creating config at {}", path.to_string_lossy().blue());
+fn print_skips(skips: &[Skip], level: Level) {
+    for skip in skips {
+        level.log(Level::Normal, format!("[-] {}", skip.render()));
+    }
+}
+
+/// Looks for the config in the current directory and its parents like git does, then in
+/// `$XDG_CONFIG_HOME/dotty`
+fn discover_config() -> Result<PathBuf> {
+    if let Some(config) = find_config_upwards()? {
+        return Ok(config);
+    }
+    let xdg = dirs::config_dir().map(|dir| dir.join("dotty").join(ROOT_FILE_NAME));
+    if let Some(config) = xdg.as_ref().filter(|path| path.is_file()) {
+        return Ok(config.clone());
+    }
+
+    let current = std::env::current_dir()?;
+    let xdg = xdg
+        .map(|path| format!(", or at {}", path.display()))
+        .unwrap_or_default();
+    Err(anyhow!(
+        "No {ROOT_FILE_NAME} found in {} or any of its parents{xdg}",
+        current.display()
+    ))
+}
+
+fn find_config_upwards() -> Result<Option<PathBuf>> {
+    let current = std::env::current_dir()?;
+    Ok(current
+        .ancestors()
+        .map(|directory| directory.join(ROOT_FILE_NAME))
+        .find(|path| path.is_file()))
+}
 
-    let config = Config::example();
-    let content = toml::to_string(&config)?;
-    std::fs::write(path, content)?;
+fn print_file_diff(config: &Config, target: &Path) -> Result<()> {
+    let desired = config.desired_contents(target)?;
+    let current = match std::fs::read(target) {
+        Ok(current) => current,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
 
+    let text = |bytes: &[u8]| {
+        std::str::from_utf8(bytes)
+            .ok()
+            .filter(|text| !text.contains('\0'))
+            .map(str::to_owned)
+    };
+    match (text(&current), text(&desired)) {
+        (Some(current), Some(desired)) => {
+            let (current, desired) = (config.redact(&current), config.redact(&desired));
+            let name = target.to_string_lossy();
+            let diff = textdiff::unified(&current, &desired, &name, &name);
+            if diff.is_empty() {
+                println!("{} is up to date", target.display());
+            } else {
+                print!("{diff}");
+            }
+        }
+        _ if current == desired => println!("{} is up to date", target.display()),
+        _ => println!("Binary file {} differs", target.display()),
+    }
     Ok(())
 }
+
+fn create_default_config(path: &Path, preset: Option<Preset>, level: Level) -> Result<()> {
+    let preset = preset.unwrap_or_else(Preset::detect);
+    level.log(
+        Level::Normal,
+        format!(
+            "Creating config at {} for {}",
+            path.to_string_lossy().blue(),
+            format!("{preset:?}").to_lowercase()
+        ),
+    );
+
+    Ok(write_config(path, &Config::preset(preset))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_tilde() {
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            expand_path(Path::new("~/dotty.toml")).unwrap(),
+            Path::new(&home).join("dotty.toml")
+        );
+    }
+
+    #[test]
+    fn expands_environment_variables() {
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            expand_path(Path::new("$HOME/state/dotty.state.toml")).unwrap(),
+            Path::new(&home).join("state/dotty.state.toml")
+        );
+        assert!(expand_path(Path::new("$DOTTY_TEST_UNSET_VARIABLE/dotty.toml")).is_err());
+    }
+
+    #[test]
+    fn leaves_relative_paths_alone() {
+        for path in ["dotty.toml", "./configs/dotty.toml", "../dotty.toml", "-"] {
+            assert_eq!(expand_path(Path::new(path)).unwrap(), Path::new(path));
+        }
+    }
+}
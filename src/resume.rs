@@ -0,0 +1,52 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::Result;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::verbosity::Level;
+
+/// Changes completed during an apply, so an interrupted apply can pick up where it stopped
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct Progress {
+    completed: HashSet<Box<str>>,
+}
+
+impl Progress {
+    pub fn read(path: &Path, level: Level) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        level.log(
+            Level::Normal,
+            format!("Resuming from {}", path.to_string_lossy().blue()),
+        );
+        let content = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let content = toml::to_string(self)?;
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+
+    pub fn remove(path: &Path) -> Result<()> {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_completed(&self, id: &str) -> bool {
+        self.completed.contains(id)
+    }
+
+    pub fn complete(&mut self, id: Box<str>) {
+        self.completed.insert(id);
+    }
+}
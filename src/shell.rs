@@ -0,0 +1,114 @@
+//! Running the commands of managers, hooks and scripts
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::host;
+
+const DEFAULT_SHELL: &str = "sh";
+
+/// The shell commands are run with as `<shell> -c <command>`, `false` runs them directly
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(untagged)]
+pub enum Shell {
+    /// `true` is `sh`
+    Enabled(bool),
+    Program(Box<str>),
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::Enabled(true)
+    }
+}
+
+impl Shell {
+    fn program(&self) -> Option<&str> {
+        match self {
+            Self::Enabled(true) => Some(DEFAULT_SHELL),
+            Self::Enabled(false) => None,
+            Self::Program(program) => Some(program),
+        }
+    }
+
+    /// Errors when the shell is not installed
+    pub fn check(&self) -> Result<()> {
+        let Some(program) = self.program() else {
+            return Ok(());
+        };
+        let found = if program.contains('/') {
+            Path::new(program).is_file()
+        } else {
+            host::has_binary(program)
+        };
+        if !found {
+            return Err(anyhow!(
+                "Shell {program} not found, set `shell` under `[dotty]` to an installed one"
+            ));
+        }
+        Ok(())
+    }
+
+    /// The process running `command`, without a shell it is split into words like a shell
+    /// would but nothing is expanded
+    pub fn command(&self, command: &str) -> Result<Command> {
+        let words = match self.program() {
+            Some(program) => vec![program.to_owned(), "-c".to_owned(), command.to_owned()],
+            None => split(command)?,
+        };
+        let Some((program, args)) = words.split_first() else {
+            return Err(anyhow!("Empty command"));
+        };
+        let mut process = Command::new(program);
+        process.args(args);
+        Ok(process)
+    }
+}
+
+/// Splits on whitespace outside of quotes, handling backslash escapes like `sh` does
+fn split(command: &str) -> Result<Vec<String>> {
+    let unterminated = || anyhow!("Unterminated quote or escape in `{command}`");
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            char if char.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or_else(unterminated)? {
+                        '\'' => break,
+                        char => word.push(char),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or_else(unterminated)? {
+                        '"' => break,
+                        '\\' => match chars.next().ok_or_else(unterminated)? {
+                            char @ ('"' | '\\' | '$' | '`') => word.push(char),
+                            char => {
+                                word.push('\\');
+                                word.push(char);
+                            }
+                        },
+                        char => word.push(char),
+                    }
+                }
+            }
+            '\\' => {
+                let char = chars.next().ok_or_else(unterminated)?;
+                word.get_or_insert_with(String::new).push(char);
+            }
+            char => word.get_or_insert_with(String::new).push(char),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
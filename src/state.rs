@@ -0,0 +1,139 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use colored::Colorize;
+use serde::Deserialize;
+use toml_edit::{DocumentMut, Item, Table};
+
+use crate::config::Config;
+use crate::verbosity::Level;
+
+/// Created next to the state, holds the process id of the run holding the lock
+const LOCK_FILE_NAME: &str = ".dotty.lock";
+
+/// Held by commands that write the state, from before they read it until they exit, so two runs
+/// can not overwrite each other's state. The lock goes away with the process, even when killed
+pub struct Lock {
+    _file: File,
+}
+
+/// Locks the state at `path`, failing right away if another run holds the lock
+pub fn lock(path: &Path) -> Result<Lock> {
+    let lock_path = path.with_file_name(LOCK_FILE_NAME);
+    // The state may live in a directory that does not exist yet, like `$XDG_STATE_HOME/dotty`
+    if let Some(parent) = lock_path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .context(format!("Could not open lock file {}", lock_path.display()))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let holder = std::fs::read_to_string(&lock_path).unwrap_or_default();
+            let holder = match holder.trim() {
+                "" => String::new(),
+                pid => format!(" (process {pid})"),
+            };
+            return Err(anyhow!(
+                "Another dotty run{holder} is using {}, wait for it to finish",
+                path.display()
+            ));
+        }
+        Err(TryLockError::Error(err)) => {
+            return Err(err).context(format!("Could not lock {}", lock_path.display()))
+        }
+    }
+
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    Ok(Lock { _file: file })
+}
+
+/// Top level keys dotty writes to the state, any other key is left alone
+fn known_keys() -> Result<HashSet<String>> {
+    let default = toml::Table::try_from(Config::default())?;
+    Ok(default.keys().cloned().collect())
+}
+
+/// Reads the state, ignoring top level keys dotty does not recognize. Unlike the config the
+/// state is not validated, it was valid when it was written.
+pub fn read(path: &Path, level: Level) -> Result<Config> {
+    level.log(
+        Level::Verbose,
+        format!("Reading state at {}", path.to_string_lossy().blue()),
+    );
+
+    let content = std::fs::read_to_string(path)?;
+    let mut table: toml::Table = toml::from_str(&content)?;
+    let known = known_keys()?;
+    table.retain(|key, _| known.contains(key));
+
+    let mut state = Config::deserialize(table)?;
+    state.load_dependencies(path)?;
+    Ok(state)
+}
+
+/// Merges the state into the existing file, so comments and unknown top level keys survive and
+/// only entries that changed are rewritten
+pub fn write(path: &Path, state: &Config, level: Level) -> Result<()> {
+    level.log(
+        Level::Verbose,
+        format!("Writing state at {}", path.to_string_lossy().blue()),
+    );
+
+    let new = toml::to_string(state)?.parse::<DocumentMut>()?;
+    let mut document = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| content.parse::<DocumentMut>().ok())
+        .unwrap_or_default();
+
+    let known = known_keys()?;
+    let root = document.as_table_mut();
+    root.retain(|key, _| !known.contains(key) || new.contains_key(key));
+    merge_entries(root, new.as_table());
+
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, document.to_string())?;
+    Ok(())
+}
+
+/// Makes `existing` match `new` while keeping the formatting of unchanged entries
+fn merge_table(existing: &mut Table, new: &Table) {
+    existing.retain(|key, _| new.contains_key(key));
+    merge_entries(existing, new);
+}
+
+fn merge_entries(existing: &mut Table, new: &Table) {
+    for (key, item) in new.iter() {
+        match (existing.get_mut(key), item) {
+            (Some(Item::Table(current)), Item::Table(item)) => merge_table(current, item),
+            (Some(Item::Value(current)), Item::Value(value)) => {
+                if current.to_string().trim() != value.to_string().trim() {
+                    let decor = current.decor().clone();
+                    *current = value.clone();
+                    *current.decor_mut() = decor;
+                }
+            }
+            (Some(current), item) => *current = item.clone(),
+            (None, item) => {
+                existing.insert(key, item.clone());
+            }
+        }
+    }
+}
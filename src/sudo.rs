@@ -0,0 +1,63 @@
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+/// How often the sudo timestamp is refreshed, well below the default timeout of 5 minutes
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keeps the sudo timestamp fresh while alive, so a long apply only asks for the password once
+pub struct KeepAlive {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl KeepAlive {
+    /// Asks for the password up front, then refreshes the timestamp in the background
+    pub fn start() -> Result<Self> {
+        Command::new("sudo")
+            .arg("-v")
+            .status()
+            .context("Could not run sudo")?
+            .exit_ok()
+            .context("sudo authentication failed")?;
+
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let handle = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let (stopped, condvar) = &*stop;
+                let mut stopped = stopped.lock().unwrap();
+                while !*stopped {
+                    stopped = condvar.wait_timeout(stopped, REFRESH_INTERVAL).unwrap().0;
+                    if !*stopped {
+                        // Never prompts, if the timestamp ran out anyway the next sudo will ask
+                        let _ = Command::new("sudo")
+                            .args(["-n", "-v"])
+                            .stdout(Stdio::null())
+                            .stderr(Stdio::null())
+                            .status();
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            stop,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        condvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
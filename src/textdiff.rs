@@ -0,0 +1,141 @@
+use colored::Colorize;
+
+/// Lines of unchanged context shown around each change
+const CONTEXT: usize = 3;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Builds a colored unified diff between two texts, empty if they are the same
+pub fn unified(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let old_lines = old.lines().collect::<Vec<_>>();
+    let new_lines = new.lines().collect::<Vec<_>>();
+    let ops = diff_lines(&old_lines, &new_lines);
+    if ops.iter().all(|op| *op == Op::Equal) {
+        return String::new();
+    }
+
+    let mut output = format!(
+        "{}\n{}\n",
+        format!("--- {old_name}").bold(),
+        format!("+++ {new_name}").bold()
+    );
+
+    // Line positions in the old and new text for each op
+    let mut positions = Vec::with_capacity(ops.len());
+    let (mut old_line, mut new_line) = (0, 0);
+    for op in ops.iter() {
+        positions.push((old_line, new_line));
+        match op {
+            Op::Equal => {
+                old_line += 1;
+                new_line += 1;
+            }
+            Op::Delete => old_line += 1,
+            Op::Insert => new_line += 1,
+        }
+    }
+
+    let changed = (0..ops.len())
+        .filter(|&i| ops[i] != Op::Equal)
+        .collect::<Vec<_>>();
+    let mut index = 0;
+    while index < changed.len() {
+        let start = changed[index].saturating_sub(CONTEXT);
+        let mut end = changed[index];
+        while index < changed.len() && changed[index] <= end + 2 * CONTEXT + 1 {
+            end = changed[index];
+            index += 1;
+        }
+        let end = (end + CONTEXT + 1).min(ops.len());
+
+        let (old_start, new_start) = positions[start];
+        let old_count = ops[start..end]
+            .iter()
+            .filter(|op| **op != Op::Insert)
+            .count();
+        let new_count = ops[start..end]
+            .iter()
+            .filter(|op| **op != Op::Delete)
+            .count();
+        output.push_str(&format!(
+            "{}\n",
+            format!(
+                "@@ -{},{old_count} +{},{new_count} @@",
+                hunk_start(old_start, old_count),
+                hunk_start(new_start, new_count)
+            )
+            .cyan()
+        ));
+
+        for (op, (old_line, new_line)) in ops[start..end].iter().zip(&positions[start..end]) {
+            let line = match op {
+                Op::Equal => format!(" {}", old_lines[*old_line]).normal(),
+                Op::Delete => format!("-{}", old_lines[*old_line]).red(),
+                Op::Insert => format!("+{}", new_lines[*new_line]).green(),
+            };
+            output.push_str(&format!("{line}\n"));
+        }
+    }
+
+    output
+}
+
+/// Hunk headers count lines from one, except for empty ranges which point at the line before
+fn hunk_start(start: usize, count: usize) -> usize {
+    if count == 0 {
+        start
+    } else {
+        start + 1
+    }
+}
+
+/// Longest common subsequence of the lines, after trimming the shared prefix and suffix
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<Op> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    // lengths[i][j] is the LCS length of old_middle[i..] and new_middle[j..]
+    let width = new_middle.len() + 1;
+    let mut lengths = vec![0u32; (old_middle.len() + 1) * width];
+    for i in (0..old_middle.len()).rev() {
+        for j in (0..new_middle.len()).rev() {
+            lengths[i * width + j] = if old_middle[i] == new_middle[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut ops = vec![Op::Equal; prefix];
+    let (mut i, mut j) = (0, 0);
+    while i < old_middle.len() && j < new_middle.len() {
+        if old_middle[i] == new_middle[j] {
+            ops.push(Op::Equal);
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            ops.push(Op::Delete);
+            i += 1;
+        } else {
+            ops.push(Op::Insert);
+            j += 1;
+        }
+    }
+    ops.extend(std::iter::repeat_n(Op::Delete, old_middle.len() - i));
+    ops.extend(std::iter::repeat_n(Op::Insert, new_middle.len() - j));
+    ops.extend(std::iter::repeat_n(Op::Equal, suffix));
+    ops
+}
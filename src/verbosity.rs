@@ -0,0 +1,51 @@
+use std::fmt::Display;
+use std::sync::Mutex;
+
+/// Names and values of the secrets resolved so far, which are never printed
+static SECRETS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// How much dotty prints, errors are printed at every level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Quiet,
+    Normal,
+    Verbose,
+    Trace,
+}
+
+impl Level {
+    pub fn from_flags(verbose: u8, quiet: bool) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Self::Quiet,
+            (false, 0) => Self::Normal,
+            (false, 1) => Self::Verbose,
+            (false, _) => Self::Trace,
+        }
+    }
+
+    /// Prints the message when running at `at` or above
+    pub fn log(self, at: Level, message: impl Display) {
+        if self >= at {
+            println!("{}", redact(&message.to_string()));
+        }
+    }
+}
+
+/// Hides `value` behind the secret's name in everything printed from now on
+pub fn hide_secret(name: &str, value: &str) {
+    if !value.is_empty() {
+        SECRETS
+            .lock()
+            .unwrap()
+            .push((name.to_owned(), value.to_owned()));
+    }
+}
+
+/// Replaces the value of every secret resolved so far with its name
+pub fn redact(text: &str) -> String {
+    let mut text = text.to_owned();
+    for (name, value) in SECRETS.lock().unwrap().iter() {
+        text = text.replace(value.as_str(), &format!("<secret {name}>"));
+    }
+    text
+}
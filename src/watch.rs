@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How often the watched paths are checked
+const POLL: Duration = Duration::from_millis(500);
+/// How long the paths have to stay unchanged before a change is acted on, so an editor saving
+/// several files at once only causes one apply
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Modification times of every watched path and everything inside watched directories,
+/// `None` for paths that do not exist
+type Snapshot = HashMap<PathBuf, Option<SystemTime>>;
+
+/// Blocks until something in `paths` is created, removed or modified and has settled
+pub fn wait_for_change(paths: &[PathBuf]) {
+    let initial = snapshot(paths);
+    let mut current = loop {
+        std::thread::sleep(POLL);
+        let current = snapshot(paths);
+        if current != initial {
+            break current;
+        }
+    };
+    loop {
+        std::thread::sleep(DEBOUNCE);
+        let next = snapshot(paths);
+        if next == current {
+            return;
+        }
+        current = next;
+    }
+}
+
+fn snapshot(paths: &[PathBuf]) -> Snapshot {
+    let mut snapshot = HashMap::new();
+    for path in paths {
+        record(path, &mut snapshot);
+    }
+    snapshot
+}
+
+fn record(path: &Path, snapshot: &mut Snapshot) {
+    let metadata = std::fs::metadata(path).ok();
+    let modified = metadata
+        .as_ref()
+        .and_then(|metadata| metadata.modified().ok());
+    snapshot.insert(path.into(), modified);
+    if !metadata.is_some_and(|metadata| metadata.is_dir()) {
+        return;
+    }
+    if let Ok(entries) = std::fs::read_dir(path) {
+        for entry in entries.flatten() {
+            record(&entry.path(), snapshot);
+        }
+    }
+}